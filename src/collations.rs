@@ -0,0 +1,205 @@
+use crate::manifest::CollationChange;
+use crate::observer::{MigrationObserver, Phase};
+use crate::{Config, db, manifest};
+use anyhow::Result;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+
+/// Name under which the database's default collation is reported.
+const DATABASE_DEFAULT: &str = "default";
+
+/// OID of the `default` collation that stands for the database's own.
+const DEFAULT_COLLATION_OID: i64 = 100;
+
+/// Checks the collation versions of the restored `db` and records the result.
+///
+/// A collation used by an index (or the database default) is reported if the
+/// target's library provides another version than the one recorded on the
+/// target (stale) or on the source. With `reindex_collations` the indexes on
+/// stale collations are rebuilt.
+///
+/// # Errors
+///
+/// Returns an error if either database cannot be queried.
+pub async fn check_db(config: &Config, db: &str, observer: &dyn MigrationObserver) -> Result<()> {
+    observer.phase_started(db, Phase::Collations, 0);
+    manifest::phase_started(config, db, Phase::Collations)?;
+
+    let result = check_collations(config, db).await;
+    if let Ok(changes) = &result {
+        for change in changes.iter().filter(|c| c.is_stale()) {
+            let message = match (&change.error, change.reindexed) {
+                (Some(error), _) => {
+                    format!("could not refresh collation {}: {error}", change.collation)
+                }
+                (None, true) => continue,
+                (None, false) => format!(
+                    "collation {} changed from {} to {}, {} indexes may be corrupt; rerun with --reindex-collations",
+                    change.collation,
+                    change.recorded.as_deref().unwrap_or("?"),
+                    change.actual.as_deref().unwrap_or("?"),
+                    change.indexes.len()
+                ),
+            };
+            observer.warning(Some(db), &message);
+        }
+        let changes = changes.clone();
+        manifest::update_database(config, db, |r| r.collation_changes = changes)?;
+    }
+    let result = result.map(|_| ());
+    manifest::phase_finished(config, db, Phase::Collations, &result)?;
+    result?;
+
+    observer.phase_finished(db, Phase::Collations);
+    Ok(())
+}
+
+/// Version of one collation as seen by one server.
+struct Version {
+    oid: i64,
+    recorded: Option<String>,
+    actual: Option<String>,
+}
+
+async fn check_collations(config: &Config, db: &str) -> Result<Vec<CollationChange>> {
+    let source = db::pg_pool(
+        &config.from_host,
+        &config.from_port,
+        &config.from_user,
+        &config.from_pass,
+        db,
+    )
+    .await?;
+    let target_db = config.target_db(db);
+    let target = db::pg_pool(
+        &config.to_host,
+        &config.to_port,
+        &config.to_user,
+        &config.to_pass,
+        target_db,
+    )
+    .await?;
+
+    let source_versions = versions(&source).await?;
+    let mut changes = Vec::new();
+    for (collation, version) in versions(&target).await? {
+        let source_version = source_versions
+            .get(&collation)
+            .and_then(|v| v.recorded.clone());
+        let stale = version.recorded.is_some() && version.recorded != version.actual;
+        let moved = source_version.is_some() && source_version != version.actual;
+        if !stale && !moved {
+            continue;
+        }
+        // A collation no index sorts by cannot corrupt anything
+        let indexes = dependent_indexes(&target, version.oid).await?;
+        if indexes.is_empty() && collation != DATABASE_DEFAULT {
+            continue;
+        }
+        let mut change = CollationChange {
+            indexes,
+            collation,
+            source_version,
+            recorded: version.recorded,
+            actual: version.actual,
+            ..CollationChange::default()
+        };
+        if stale && config.reindex_collations {
+            match refresh(&target, target_db, &change).await {
+                Ok(()) => change.reindexed = true,
+                Err(e) => change.error = Some(format!("{e:#}")),
+            }
+        }
+        changes.push(change);
+    }
+    Ok(changes)
+}
+
+/// Returns the recorded and actual version of every versioned collation usable
+/// in the connected database, keyed by qualified name.
+async fn versions(pool: &PgPool) -> Result<BTreeMap<String, Version>> {
+    let server_version: String = sqlx::query_scalar("SHOW server_version_num")
+        .fetch_one(pool)
+        .await?;
+    let server_version: u32 = server_version.parse()?;
+    let mut versions = BTreeMap::new();
+
+    // Collation versions are tracked since PostgreSQL 10, the database
+    // default's since 15
+    if server_version >= 100_000 {
+        let rows: Vec<(i64, String, Option<String>, Option<String>)> = sqlx::query_as(
+            "SELECT c.oid::int8, format('%I.%I', n.nspname, c.collname), c.collversion, \
+                    pg_collation_actual_version(c.oid) \
+             FROM pg_collation c JOIN pg_namespace n ON n.oid = c.collnamespace \
+             WHERE c.collversion IS NOT NULL \
+               AND c.collencoding IN (-1, (SELECT encoding FROM pg_database \
+                                           WHERE datname = current_database()))",
+        )
+        .fetch_all(pool)
+        .await?;
+        for (oid, name, recorded, actual) in rows {
+            versions.insert(
+                name,
+                Version {
+                    oid,
+                    recorded,
+                    actual,
+                },
+            );
+        }
+    }
+    if server_version >= 150_000 {
+        let (recorded, actual): (Option<String>, Option<String>) = sqlx::query_as(
+            "SELECT datcollversion, pg_database_collation_actual_version(oid) \
+             FROM pg_database WHERE datname = current_database()",
+        )
+        .fetch_one(pool)
+        .await?;
+        if recorded.is_some() {
+            versions.insert(
+                DATABASE_DEFAULT.to_string(),
+                Version {
+                    oid: DEFAULT_COLLATION_OID,
+                    recorded,
+                    actual,
+                },
+            );
+        }
+    }
+    Ok(versions)
+}
+
+/// Returns the user indexes that sort by the collation `oid`.
+async fn dependent_indexes(pool: &PgPool, oid: i64) -> Result<Vec<String>> {
+    Ok(sqlx::query_scalar(
+        "SELECT DISTINCT i.indexrelid::regclass::text \
+         FROM pg_index i \
+         JOIN pg_class c ON c.oid = i.indexrelid \
+         JOIN pg_namespace n ON n.oid = c.relnamespace \
+         WHERE ($1::int8)::oid = ANY (i.indcollation::oid[]) \
+           AND n.nspname NOT IN ('pg_catalog', 'pg_toast', 'information_schema') \
+         ORDER BY 1",
+    )
+    .bind(oid)
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Rebuilds the indexes of a stale collation and records its current version.
+async fn refresh(pool: &PgPool, target_db: &str, change: &CollationChange) -> Result<()> {
+    for index in &change.indexes {
+        sqlx::query(&format!("REINDEX INDEX {index}"))
+            .execute(pool)
+            .await?;
+    }
+    let statement = if change.collation == DATABASE_DEFAULT {
+        format!(
+            "ALTER DATABASE \"{}\" REFRESH COLLATION VERSION",
+            target_db.replace('"', "\"\"")
+        )
+    } else {
+        format!("ALTER COLLATION {} REFRESH VERSION", change.collation)
+    };
+    sqlx::query(&statement).execute(pool).await?;
+    Ok(())
+}
@@ -0,0 +1,474 @@
+use crate::config::RestartPolicy;
+use crate::dumps::{self, DumpAction};
+use crate::manifest::{self, Manifest};
+use crate::observer::{MigrationObserver, Phase};
+use crate::phases::{
+    phase_compute_source_counts, phase_dump_all, phase_pipeline_all, phase_restore_all,
+    phase_verify_all,
+};
+use crate::preflight::{self, CheckStatus};
+use crate::script::{self, ScriptItem};
+use crate::tui::{render_checks, render_command, render_plan};
+use crate::{Config, compat, db, extensions, roles, schedule, settings};
+use anyhow::Result;
+use log::info;
+use std::{collections::BTreeMap, fmt::Write, fs, sync::Arc};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+/// Discovers the databases to migrate and prepares the state directories.
+async fn discover(config: &Config, observer: &dyn MigrationObserver) -> Result<Vec<(String, u64)>> {
+    fs::create_dir_all(config.state_dir())?;
+
+    let dbs_with_sizes = db::discover_databases(config).await?;
+    record_environment(config, &dbs_with_sizes).await?;
+    let db_names: Vec<&String> = dbs_with_sizes.iter().map(|(n, _)| n).collect();
+    info!("Databases: {db_names:?}");
+
+    if dbs_with_sizes.is_empty() {
+        info!("No databases found to migrate.");
+    }
+    observer.databases_discovered(&dbs_with_sizes);
+    Ok(dbs_with_sizes)
+}
+
+/// Stores the selected databases and the server and tool versions in the manifest.
+async fn record_environment(config: &Config, dbs_with_sizes: &[(String, u64)]) -> Result<()> {
+    let server_version = db::source_server_version(config).await.ok();
+    let mut tool_versions = BTreeMap::new();
+    for program in ["pg_dump", "pg_restore", "pg_dumpall", "vacuumdb"] {
+        if let Some(version) = db::tool_version(program).await {
+            tool_versions.insert(program.to_string(), version);
+        }
+    }
+    manifest::update(config, |m| {
+        m.source_server_version = server_version;
+        m.tool_versions = tool_versions;
+    })?;
+    manifest::record_databases(config, dbs_with_sizes)
+}
+
+fn names(dbs_with_sizes: &[(String, u64)]) -> Vec<String> {
+    dbs_with_sizes.iter().map(|(n, _)| n.clone()).collect()
+}
+
+/// Runs every phase: globals, dump, restore and verification.
+///
+/// # Errors
+///
+/// Returns the first error from any phase.
+pub async fn run(
+    config: &Config,
+    observer: &Arc<dyn MigrationObserver>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let dbs_with_sizes = discover(config, &**observer).await?;
+    if dbs_with_sizes.is_empty() {
+        return Ok(());
+    }
+    let config = &schedule::with_job_shares(config, &dbs_with_sizes);
+    let db_names = names(&dbs_with_sizes);
+    compat::scan_all(config, &db_names, &**observer).await?;
+    extensions::check_all(config, &db_names, &**observer).await?;
+
+    settings::with_fast_restore(config, async {
+        if config.migrate_globals {
+            db::migrate_globals(config, &**observer).await?;
+        }
+
+        db::create_dbs(config, &db_names, &**observer).await?;
+
+        // Every database goes through dump, restore and verify on its own, so
+        // small databases finish while large ones are still being dumped
+        let sem = Arc::new(Semaphore::new(config.max_parallel));
+        phase_pipeline_all(config, &dbs_with_sizes, observer, cancel, sem).await?;
+        roles::drop_placeholders(config, &db_names, &**observer).await
+    })
+    .await
+}
+
+/// Which parts of a run [`dry_run`] describes.
+#[derive(Clone, Copy, Debug)]
+pub struct DryRunScope {
+    pub settings: bool,
+    pub globals: bool,
+    pub dump: bool,
+    pub restore: bool,
+}
+
+impl DryRunScope {
+    pub const ALL: Self = Self {
+        settings: true,
+        globals: true,
+        dump: true,
+        restore: true,
+    };
+    pub const NONE: Self = Self {
+        settings: false,
+        globals: false,
+        dump: false,
+        restore: false,
+    };
+    pub const DUMP: Self = Self {
+        dump: true,
+        ..Self::NONE
+    };
+    pub const RESTORE: Self = Self {
+        settings: true,
+        globals: true,
+        restore: true,
+        ..Self::NONE
+    };
+    pub const GLOBALS: Self = Self {
+        globals: true,
+        ..Self::NONE
+    };
+}
+
+/// Prints what a run would do without changing anything.
+///
+/// The source is read for discovery and `pg_dumpall`, and the target only for
+/// the settings it can take; nothing is written to the target, the dump
+/// directory or the state directories.
+///
+/// # Errors
+///
+/// Returns an error if the source cannot be queried or `pg_dumpall` fails.
+pub async fn dry_run(config: &Config, scope: DryRunScope) -> Result<()> {
+    let dbs_with_sizes = db::discover_databases(config).await?;
+    let config = &schedule::with_job_shares(config, &dbs_with_sizes);
+    let manifest = manifest::load(config)?;
+    let mut out = render_plan(config, &manifest, &dbs_with_sizes);
+
+    if scope.settings {
+        plan_settings(config, &manifest, &mut out).await?;
+    }
+
+    if scope.globals {
+        plan_globals(config, &manifest, &mut out).await?;
+    }
+
+    if scope.restore {
+        out.push_str("\nTarget databases:\n");
+        for (db, _) in &dbs_with_sizes {
+            let _ = writeln!(out, "  CREATE DATABASE \"{}\";", config.target_db(db));
+        }
+    }
+
+    if config.stream && (scope.dump || scope.restore) {
+        let _ = writeln!(
+            out,
+            "\nStreams (up to {} databases at a time):",
+            config.max_parallel
+        );
+        for (db, _) in &dbs_with_sizes {
+            let target_db = config.target_db(db);
+            if manifest.is_finished(config, db, Phase::Restore) {
+                let _ = writeln!(out, "  {db}: already restored into {target_db}");
+            } else {
+                let clean = manifest.restore_attempted(config, db);
+                let dump = render_command("pg_dump", &db::pg_dump_stream_args(config, db));
+                let restore =
+                    render_command("pg_restore", &db::pg_restore_stream_args(config, db, clean));
+                let _ = writeln!(out, "  {dump} | {restore}");
+            }
+        }
+    }
+
+    if scope.dump && !config.stream {
+        plan_dumps(config, &dbs_with_sizes, &mut out).await?;
+    }
+
+    if scope.restore && !config.stream {
+        let _ = writeln!(
+            out,
+            "\nRestores (up to {} databases at a time):",
+            config.max_parallel
+        );
+        for (db, _) in &dbs_with_sizes {
+            let target_db = config.target_db(db);
+            if manifest.is_finished(config, db, Phase::Restore) {
+                let _ = writeln!(out, "  {db}: already restored into {target_db}");
+            } else {
+                let dump_path = db::dump_dir(&config.dump_root, db);
+                let clean = manifest.restore_attempted(config, db);
+                let args = db::pg_restore_args(config, db, &dump_path, clean);
+                let _ = writeln!(out, "  {}", render_command("pg_restore", &args));
+            }
+        }
+    }
+
+    if config.analyze && scope.restore {
+        out.push_str("\nAnalyze:\n");
+        for (db, _) in &dbs_with_sizes {
+            let args = db::vacuumdb_args(config, db);
+            let _ = writeln!(out, "  {}", render_command("vacuumdb", &args));
+        }
+    }
+
+    print!("{out}");
+    Ok(())
+}
+
+/// Appends the target settings a run would change to `out`.
+async fn plan_settings(config: &Config, manifest: &Manifest, out: &mut String) -> Result<()> {
+    out.push_str("\nTarget settings:\n");
+    if config.disable_dst_optimizations {
+        out.push_str("  (unchanged, --disable-dst-optimizations)\n");
+        return Ok(());
+    }
+    let _ = writeln!(out, "  -- profile {}", config.fast_restore_profile);
+    let skipped = match settings::plan(config).await {
+        Ok((applied, skipped)) => {
+            for sql in settings::fast_restore_statements(&applied) {
+                let _ = writeln!(out, "  {sql}");
+            }
+            skipped
+        }
+        Err(e) => {
+            let _ = writeln!(
+                out,
+                "  -- cannot read the target settings ({e:#}); settings it cannot take are skipped when the run starts"
+            );
+            for sql in settings::fast_restore_statements(&settings::profile(config)?) {
+                let _ = writeln!(out, "  {sql}");
+            }
+            BTreeMap::new()
+        }
+    };
+    for (name, reason) in skipped {
+        let _ = writeln!(out, "  -- skipped {name}: {reason}");
+    }
+    if config.restart_policy == RestartPolicy::Prompt {
+        out.push_str("  -- settings that need a restart are applied after a restart prompt\n");
+    }
+    match &manifest.target_settings {
+        Some(snapshot) => {
+            out.push_str("  -- reverted to the values recorded by an earlier run with:\n");
+            for sql in settings::revert_statements(snapshot) {
+                let _ = writeln!(out, "  {sql}");
+            }
+        }
+        None => out.push_str(
+            "  -- the original values are recorded first and put back after the restore\n",
+        ),
+    }
+    Ok(())
+}
+
+/// Appends the globals statements a run would execute to `out`.
+async fn plan_globals(config: &Config, manifest: &Manifest, out: &mut String) -> Result<()> {
+    out.push_str("\nGlobals:\n");
+    if !config.migrate_globals {
+        out.push_str("  (skipped, --migrate-globals false)\n");
+    } else if manifest.globals_finished() {
+        out.push_str("  (already migrated by an earlier run)\n");
+    } else {
+        let globals = roles::filter_globals(config, &db::dump_globals(config).await?)?;
+        for item in script::split(&globals) {
+            match item {
+                ScriptItem::Sql(sql) => {
+                    for line in sql.lines().filter(|l| !l.trim().is_empty()) {
+                        let _ = writeln!(out, "  {line}");
+                    }
+                }
+                ScriptItem::Meta(command) => {
+                    let _ = writeln!(out, "  -- skipped psql meta-command: {command}");
+                }
+            }
+        }
+    }
+    for line in roles::plan(config) {
+        let _ = writeln!(out, "  {line}");
+    }
+    Ok(())
+}
+
+/// Appends the `pg_dump` command lines a run would execute to `out`.
+async fn plan_dumps(
+    config: &Config,
+    dbs_with_sizes: &[(String, u64)],
+    out: &mut String,
+) -> Result<()> {
+    let _ = writeln!(
+        out,
+        "\nDumps (up to {} databases at a time):",
+        config.max_parallel
+    );
+    for (db, _) in dbs_with_sizes {
+        let dump_path = db::dump_dir(&config.dump_root, db);
+        if dump_path.join("toc.dat").exists() {
+            let problem = dumps::check_dump(config, db, &dump_path).await?;
+            let note = problem
+                .as_ref()
+                .map(|p| format!(" (dump {p})"))
+                .unwrap_or_default();
+            match dumps::decide(config, db, problem.as_ref()) {
+                Ok(DumpAction::Reuse) => {
+                    let _ = writeln!(out, "  {db}: reusing {}{note}", dump_path.display());
+                    continue;
+                }
+                Ok(DumpAction::Redump) => {
+                    let _ = writeln!(out, "  {db}: dumping again{note}");
+                }
+                Err(e) => {
+                    let _ = writeln!(out, "  {db}: would fail: {e}");
+                    continue;
+                }
+            }
+        }
+        let args = db::pg_dump_args(config, db, &dump_path);
+        let _ = writeln!(out, "  {}", render_command("pg_dump", &args));
+    }
+    Ok(())
+}
+
+/// Dumps every database and records the source row counts.
+///
+/// # Errors
+///
+/// Returns the first error from any phase.
+pub async fn dump(
+    config: &Config,
+    observer: &Arc<dyn MigrationObserver>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    if config.stream {
+        anyhow::bail!("streaming keeps no dumps; use `run --stream` instead of `dump`");
+    }
+    let dbs_with_sizes = discover(config, &**observer).await?;
+    if dbs_with_sizes.is_empty() {
+        return Ok(());
+    }
+    let config = &schedule::with_job_shares(config, &dbs_with_sizes);
+    compat::scan_all(config, &names(&dbs_with_sizes), &**observer).await?;
+    let sem = Arc::new(Semaphore::new(config.max_parallel));
+
+    phase_dump_all(config, &dbs_with_sizes, observer, cancel, sem).await?;
+    phase_compute_source_counts(config, &names(&dbs_with_sizes), &**observer).await
+}
+
+/// Restores previously taken dumps into the target.
+///
+/// Globals are migrated first (unless disabled or already done) so that
+/// object owners exist by the time `pg_restore` runs.
+///
+/// # Errors
+///
+/// Returns the first error from any phase.
+pub async fn restore(
+    config: &Config,
+    observer: &Arc<dyn MigrationObserver>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    if config.stream {
+        anyhow::bail!("streaming keeps no dumps; use `run --stream` instead of `restore`");
+    }
+    let dbs_with_sizes = discover(config, &**observer).await?;
+    if dbs_with_sizes.is_empty() {
+        return Ok(());
+    }
+    let config = &schedule::with_job_shares(config, &dbs_with_sizes);
+    let db_names = names(&dbs_with_sizes);
+    compat::scan_all(config, &db_names, &**observer).await?;
+    extensions::check_all(config, &db_names, &**observer).await?;
+
+    settings::with_fast_restore(config, async {
+        if config.migrate_globals {
+            db::migrate_globals(config, &**observer).await?;
+        }
+
+        db::create_dbs(config, &db_names, &**observer).await?;
+
+        let sem = Arc::new(Semaphore::new(config.max_parallel));
+        phase_restore_all(config, &dbs_with_sizes, observer, cancel, sem).await?;
+        roles::drop_placeholders(config, &db_names, &**observer).await
+    })
+    .await
+}
+
+/// Checks connectivity, privileges, client tools and disk space and prints
+/// the results as a table.
+///
+/// Nothing is written, not even the state directory.
+///
+/// # Errors
+///
+/// Returns an error if any check failed.
+pub async fn preflight(config: &Config) -> Result<()> {
+    let checks = preflight::run_checks(config).await;
+    print!("{}", render_checks(&checks));
+    let failed = checks
+        .iter()
+        .filter(|c| c.status == CheckStatus::Fail)
+        .count();
+    if failed > 0 {
+        anyhow::bail!("preflight failed: {failed} checks did not pass");
+    }
+    Ok(())
+}
+
+/// Takes the target out of a fast restore mode an earlier run left behind,
+/// finishes its role renames and prints the statements used; with `dry_run`
+/// they are only printed.
+///
+/// # Errors
+///
+/// Returns an error if the target cannot be queried or changed.
+pub async fn recover(config: &Config) -> Result<()> {
+    let statements = settings::recover(config).await?;
+    if statements.is_empty() {
+        println!("Target is not in fast restore mode; nothing to recover.");
+    } else {
+        println!(
+            "Target settings {}:",
+            if config.dry_run {
+                "would be put back with"
+            } else {
+                "put back with"
+            }
+        );
+        for sql in statements {
+            println!("  {sql}");
+        }
+    }
+
+    let statements = roles::recover(config).await?;
+    if !statements.is_empty() {
+        println!(
+            "Role renames {}:",
+            if config.dry_run {
+                "would be finished with"
+            } else {
+                "finished with"
+            }
+        );
+        for sql in statements {
+            println!("  {sql}");
+        }
+    }
+    Ok(())
+}
+
+/// Compares row counts between source and target.
+///
+/// # Errors
+///
+/// Returns the first error from any phase.
+pub async fn verify(config: &Config, observer: &dyn MigrationObserver) -> Result<()> {
+    let dbs_with_sizes = discover(config, observer).await?;
+    if dbs_with_sizes.is_empty() {
+        return Ok(());
+    }
+    phase_verify_all(config, &names(&dbs_with_sizes), observer).await
+}
+
+/// Migrates roles and other global objects only.
+///
+/// # Errors
+///
+/// Returns an error if `pg_dumpall` fails or the target cannot be reached.
+pub async fn globals(config: &Config, observer: &dyn MigrationObserver) -> Result<()> {
+    fs::create_dir_all(config.state_dir())?;
+    db::migrate_globals(config, observer).await
+}
@@ -0,0 +1,344 @@
+use crate::observer::{MigrationObserver, Phase};
+use crate::{Config, db, manifest};
+use anyhow::{Context, Result};
+use sqlx::PgPool;
+use std::fmt;
+
+/// How bad an incompatible object is for the migration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The object comes over, but changes or breaks when used.
+    Warning,
+    /// `pg_restore` fails on the object.
+    Fatal,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warning => "warning",
+            Self::Fatal => "fatal",
+        })
+    }
+}
+
+/// One object of a source database that the target version cannot take as is.
+#[derive(Clone, Debug)]
+pub struct Finding {
+    /// Name of the rule that found the object.
+    pub rule: &'static str,
+    pub severity: Severity,
+    /// Where the object lives, e.g. `public.orders.created` for a column.
+    pub location: String,
+    pub detail: String,
+}
+
+/// A query for objects that break when moving across `breaks_in`.
+struct Rule {
+    name: &'static str,
+    /// First major version on which the objects no longer work.
+    breaks_in: u32,
+    severity: Severity,
+    /// Returns `(location, detail)` rows; `{user}` is replaced by a filter on
+    /// `n.nspname` that leaves out the system schemas, `{xlog}` and
+    /// `{changed}` by the function names the rule looks for.
+    query: &'static str,
+}
+
+const USER_SCHEMAS: &str = "n.nspname NOT IN ('pg_catalog', 'information_schema') \
+                            AND n.nspname NOT LIKE 'pg\\_toast%'";
+
+/// Functions renamed from `xlog` to `wal` in `PostgreSQL` 10.
+const XLOG_FUNCTIONS: &str = "'\\mpg_(current_xlog|xlogfile_name|xlog_location_diff|\
+                              last_xlog|is_xlog_replay|xlog_replay|switch_xlog)'";
+
+/// Array functions whose `anyarray` arguments became `anycompatiblearray` in
+/// `PostgreSQL` 14, the ones `pg_upgrade` checks for.
+const CHANGED_ARRAY_FUNCTIONS: &[&str] = &[
+    "array_append",
+    "array_prepend",
+    "array_cat",
+    "array_remove",
+    "array_replace",
+    "array_position",
+    "array_positions",
+    "width_bucket",
+];
+
+const RULES: &[Rule] = &[
+    Rule {
+        name: "xlog_functions_in_views",
+        breaks_in: 10,
+        severity: Severity::Fatal,
+        query: "SELECT format('%I.%I', n.nspname, c.relname), \
+                'view calls an xlog function renamed to wal' \
+                FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace \
+                WHERE c.relkind IN ('v', 'm') AND {user} \
+                AND pg_get_viewdef(c.oid) ~ {xlog}",
+    },
+    Rule {
+        name: "xlog_functions_in_functions",
+        breaks_in: 10,
+        severity: Severity::Warning,
+        query: "SELECT format('%I.%s', n.nspname, p.oid::regprocedure), \
+                'calls an xlog function renamed to wal and fails when run' \
+                FROM pg_proc p JOIN pg_namespace n ON n.oid = p.pronamespace \
+                WHERE {user} AND p.prosrc ~ {xlog}",
+    },
+    Rule {
+        name: "with_oids",
+        breaks_in: 12,
+        severity: Severity::Warning,
+        query: "SELECT format('%I.%I', n.nspname, c.relname), \
+                'table declared WITH OIDS; its oid column is dropped' \
+                FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace \
+                WHERE c.relhasoids AND c.relkind = 'r' AND {user}",
+    },
+    Rule {
+        name: "removed_time_types",
+        breaks_in: 12,
+        severity: Severity::Fatal,
+        query: "SELECT format('%I.%I.%I', n.nspname, c.relname, a.attname), \
+                format('column of removed type %s', t.typname) \
+                FROM pg_attribute a \
+                JOIN pg_class c ON c.oid = a.attrelid \
+                JOIN pg_namespace n ON n.oid = c.relnamespace \
+                JOIN pg_type t ON t.oid = a.atttypid \
+                WHERE t.typname IN ('abstime', 'reltime', 'tinterval', \
+                                    '_abstime', '_reltime', '_tinterval') \
+                AND t.typnamespace = 'pg_catalog'::regnamespace \
+                AND a.attnum > 0 AND NOT a.attisdropped \
+                AND c.relkind IN ('r', 'p', 'm', 'v', 'f', 'c') AND {user}",
+    },
+    Rule {
+        name: "sql_identifier_columns",
+        breaks_in: 12,
+        severity: Severity::Warning,
+        query: "SELECT format('%I.%I.%I', n.nspname, c.relname, a.attname), \
+                'sql_identifier is now based on name; values longer than 63 bytes are truncated' \
+                FROM pg_attribute a \
+                JOIN pg_class c ON c.oid = a.attrelid \
+                JOIN pg_namespace n ON n.oid = c.relnamespace \
+                WHERE a.atttypid = 'information_schema.sql_identifier'::regtype \
+                AND a.attnum > 0 AND NOT a.attisdropped \
+                AND c.relkind IN ('r', 'p', 'm') AND {user}",
+    },
+    Rule {
+        name: "postfix_operators",
+        breaks_in: 14,
+        severity: Severity::Fatal,
+        query: "SELECT format('%I.%s', n.nspname, o.oid::regoperator), \
+                'postfix operators are no longer supported' \
+                FROM pg_operator o JOIN pg_namespace n ON n.oid = o.oprnamespace \
+                WHERE o.oprright = 0 AND o.oprleft <> 0 AND {user}",
+    },
+    Rule {
+        name: "polymorphic_array_functions",
+        breaks_in: 14,
+        severity: Severity::Fatal,
+        query: "SELECT format('%s', a.aggfnoid::regprocedure), \
+                format('aggregate uses %s, whose signature changed to anycompatible', f.proname) \
+                FROM pg_aggregate a \
+                JOIN pg_proc p ON p.oid = a.aggfnoid \
+                JOIN pg_namespace n ON n.oid = p.pronamespace \
+                JOIN pg_proc f ON f.oid IN (a.aggtransfn, a.aggfinalfn) \
+                WHERE {user} AND f.pronamespace = 'pg_catalog'::regnamespace \
+                AND f.proname IN ({changed}) \
+                AND 'anyarray'::regtype = ANY (f.proargtypes::oid[]) \
+                UNION ALL \
+                SELECT format('%I.%s', n.nspname, o.oid::regoperator), \
+                format('operator uses %s, whose signature changed to anycompatible', f.proname) \
+                FROM pg_operator o \
+                JOIN pg_namespace n ON n.oid = o.oprnamespace \
+                JOIN pg_proc f ON f.oid = o.oprcode \
+                WHERE {user} AND f.pronamespace = 'pg_catalog'::regnamespace \
+                AND f.proname IN ({changed}) \
+                AND 'anyarray'::regtype = ANY (f.proargtypes::oid[])",
+    },
+];
+
+impl Rule {
+    /// Returns the query with its placeholders filled in.
+    fn sql(&self) -> String {
+        let functions = CHANGED_ARRAY_FUNCTIONS
+            .iter()
+            .map(|f| format!("'{f}'"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.query
+            .replace("{user}", USER_SCHEMAS)
+            .replace("{xlog}", XLOG_FUNCTIONS)
+            .replace("{changed}", &functions)
+    }
+}
+
+/// Returns the major version of the server behind `pool`.
+async fn server_major(pool: &PgPool) -> Result<u32> {
+    let version: String = sqlx::query_scalar("SHOW server_version_num")
+        .fetch_one(pool)
+        .await?;
+    let version: u32 = version.parse().context("unexpected server_version_num")?;
+    Ok(version / 10000)
+}
+
+/// Major versions of the source and target servers.
+#[derive(Clone, Copy, Debug)]
+pub struct Versions {
+    pub source: u32,
+    pub target: u32,
+}
+
+impl Versions {
+    /// Reads the major versions of both servers.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either server cannot be queried.
+    pub async fn load(config: &Config) -> Result<Self> {
+        let source = db::pg_pool(
+            &config.from_host,
+            &config.from_port,
+            &config.from_user,
+            &config.from_pass,
+            &config.from_db,
+        )
+        .await?;
+        let target = db::pg_pool(
+            &config.to_host,
+            &config.to_port,
+            &config.to_user,
+            &config.to_pass,
+            &config.to_db,
+        )
+        .await?;
+        Ok(Self {
+            source: server_major(&source).await?,
+            target: server_major(&target).await?,
+        })
+    }
+}
+
+/// Scans source database `db` for objects that the target version cannot
+/// take as is.
+///
+/// # Errors
+///
+/// Returns an error if the source database cannot be queried.
+pub async fn scan_db(config: &Config, db: &str, versions: Versions) -> Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    if versions.target < versions.source {
+        findings.push(Finding {
+            rule: "downgrade",
+            severity: Severity::Fatal,
+            location: db.to_string(),
+            detail: format!(
+                "PostgreSQL {} dumps are not guaranteed to load into {}",
+                versions.source, versions.target
+            ),
+        });
+    }
+
+    let rules: Vec<&Rule> = RULES
+        .iter()
+        .filter(|r| versions.source < r.breaks_in && r.breaks_in <= versions.target)
+        .collect();
+    if rules.is_empty() {
+        return Ok(findings);
+    }
+
+    let pool = db::pg_pool(
+        &config.from_host,
+        &config.from_port,
+        &config.from_user,
+        &config.from_pass,
+        db,
+    )
+    .await?;
+    for rule in rules {
+        let rows: Vec<(String, String)> = sqlx::query_as(&rule.sql())
+            .fetch_all(&pool)
+            .await
+            .with_context(|| format!("compatibility rule {} failed on {db}", rule.name))?;
+        findings.extend(rows.into_iter().map(|(location, detail)| Finding {
+            rule: rule.name,
+            severity: rule.severity,
+            location,
+            detail,
+        }));
+    }
+    Ok(findings)
+}
+
+/// Scans every database that still has to be restored and reports the
+/// findings to the observer.
+///
+/// # Errors
+///
+/// Returns an error if a server cannot be queried, or if there are fatal
+/// findings and `ignore_incompatible` is not set.
+pub async fn scan_all(
+    config: &Config,
+    dbs: &[String],
+    observer: &dyn MigrationObserver,
+) -> Result<()> {
+    let manifest = manifest::load(config)?;
+    let versions = Versions::load(config).await?;
+    let mut fatal = 0;
+    for db in dbs {
+        if manifest.is_finished(config, db, Phase::Restore) {
+            continue;
+        }
+        let findings = scan_db(config, db, versions).await?;
+        fatal += findings
+            .iter()
+            .filter(|f| f.severity == Severity::Fatal)
+            .count();
+        observer.incompatibilities(db, &findings);
+    }
+    if fatal > 0 && !config.ignore_incompatible {
+        anyhow::bail!(
+            "{fatal} objects cannot be restored on PostgreSQL {}; fix them on the source or use --ignore-incompatible",
+            versions.target
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str) -> &'static Rule {
+        RULES.iter().find(|r| r.name == name).expect("rule exists")
+    }
+
+    #[test]
+    fn polymorphic_rule_only_matches_changed_functions() {
+        let sql = rule("polymorphic_array_functions").sql();
+        assert!(!sql.contains("{changed}"));
+        for function in CHANGED_ARRAY_FUNCTIONS {
+            assert!(sql.contains(&format!("'{function}'")));
+        }
+        for function in ["array_length", "arraycontains", "array_to_string"] {
+            assert!(!sql.contains(function), "{function} is flagged");
+        }
+    }
+
+    #[test]
+    fn polymorphic_rule_does_not_scan_views() {
+        let sql = rule("polymorphic_array_functions").sql();
+        assert!(!sql.contains("pg_get_viewdef"));
+        assert!(!sql.contains("relkind"));
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        for rule in RULES {
+            assert!(
+                !rule.sql().contains('{'),
+                "{} keeps a placeholder",
+                rule.name
+            );
+        }
+    }
+}
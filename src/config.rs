@@ -0,0 +1,617 @@
+use crate::filter::NameFilter;
+use crate::settings;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+#[derive(Clone)]
+pub struct Config {
+    pub from_host: String,
+    pub from_port: String,
+    pub from_user: String,
+    pub from_pass: String,
+    pub from_db: String,
+
+    pub to_host: String,
+    pub to_port: String,
+    pub to_user: String,
+    pub to_pass: String,
+    pub to_db: String,
+
+    pub dump_jobs: usize,
+    pub restore_jobs: usize,
+    pub max_parallel: usize,
+    pub compression: String,
+    /// Upper bound on `pg_dump`/`pg_restore` jobs across all running databases.
+    pub jobs_budget: Option<usize>,
+    pub order: ScheduleOrder,
+    /// Databases matching these patterns start first, in pattern order.
+    pub priority_dbs: Vec<String>,
+
+    pub dump_root: PathBuf,
+    /// Root of the state directories; `$HOME/pg_migrate_state` when unset.
+    pub state_root: Option<PathBuf>,
+    /// Names the run's state directory; derived from the endpoints when unset.
+    pub run_id: Option<String>,
+    /// What to do with an existing dump that is incomplete, unreadable,
+    /// from another source or too old.
+    pub stale_dump_policy: StaleDumpPolicy,
+    /// Existing dumps older than this are stale.
+    pub max_dump_age: Option<Duration>,
+    pub migrate_globals: bool,
+    pub disable_dst_optimizations: bool,
+    /// Name of the fast restore profile: `conservative`, `default`,
+    /// `aggressive` or one from `fast_restore_profiles`.
+    pub fast_restore_profile: String,
+    /// Custom fast restore profiles, keyed by name; each maps setting names
+    /// to values.
+    pub fast_restore_profiles: BTreeMap<String, BTreeMap<String, String>>,
+    /// What to do with profile settings that only take effect after a restart.
+    pub restart_policy: RestartPolicy,
+    /// Pipe `pg_dump` into `pg_restore` instead of keeping dumps on disk.
+    pub stream: bool,
+    /// Run `ALTER EXTENSION ... UPDATE` in every database after its restore.
+    pub update_extensions: bool,
+    /// Run `vacuumdb --analyze-in-stages` in every database after its restore.
+    pub analyze: bool,
+    /// Rebuild indexes on collations whose version changed and refresh the
+    /// recorded version.
+    pub reindex_collations: bool,
+    /// Go ahead even if the compatibility scan finds objects that cannot be
+    /// restored on the target version.
+    pub ignore_incompatible: bool,
+    /// Print what would be done instead of doing it.
+    pub dry_run: bool,
+
+    pub include_dbs: Vec<String>,
+    pub exclude_dbs: Vec<String>,
+    pub include_postgres_db: bool,
+    pub databases: BTreeMap<String, DatabaseOverride>,
+    /// Roles matching these patterns are left out of the globals.
+    pub exclude_roles: Vec<String>,
+    /// Source role names mapped to the names they get on the target.
+    pub role_renames: BTreeMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            from_host: "localhost".into(),
+            from_port: "5432".into(),
+            from_user: "postgres".into(),
+            from_pass: "oldpass".into(),
+            from_db: "postgres".into(),
+            to_host: "localhost".into(),
+            to_port: "5432".into(),
+            to_user: "postgres".into(),
+            to_pass: "newpass".into(),
+            to_db: "postgres".into(),
+            dump_jobs: 24,
+            restore_jobs: 12,
+            max_parallel: 6,
+            compression: "zstd:5".into(),
+            jobs_budget: None,
+            order: ScheduleOrder::default(),
+            priority_dbs: Vec::new(),
+            dump_root: "pg_dumps".into(),
+            state_root: None,
+            run_id: None,
+            stale_dump_policy: StaleDumpPolicy::default(),
+            max_dump_age: None,
+            migrate_globals: true,
+            disable_dst_optimizations: false,
+            fast_restore_profile: "default".into(),
+            fast_restore_profiles: BTreeMap::new(),
+            restart_policy: RestartPolicy::default(),
+            stream: false,
+            update_extensions: false,
+            analyze: false,
+            reindex_collations: false,
+            ignore_incompatible: false,
+            dry_run: false,
+            include_dbs: Vec::new(),
+            exclude_dbs: Vec::new(),
+            include_postgres_db: false,
+            databases: BTreeMap::new(),
+            exclude_roles: Vec::new(),
+            role_renames: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Applies the values set in a config file on top of the current ones.
+    pub fn apply_file(&mut self, file: FileConfig) {
+        let FileConfig {
+            source,
+            target,
+            jobs,
+            dump_jobs,
+            restore_jobs,
+            max_parallel,
+            compression,
+            jobs_budget,
+            order,
+            priority,
+            dump_root,
+            state_dir,
+            run_id,
+            stale_dump_policy,
+            max_dump_age,
+            migrate_globals,
+            disable_dst_optimizations,
+            fast_restore_profile,
+            fast_restore_profiles,
+            restart_policy,
+            stream,
+            update_extensions,
+            analyze,
+            reindex_collations,
+            ignore_incompatible,
+            include,
+            exclude,
+            include_postgres_db,
+            databases,
+            exclude_roles,
+            rename_roles,
+        } = file;
+
+        source.apply(
+            &mut self.from_host,
+            &mut self.from_port,
+            &mut self.from_user,
+            &mut self.from_pass,
+            &mut self.from_db,
+        );
+        target.apply(
+            &mut self.to_host,
+            &mut self.to_port,
+            &mut self.to_user,
+            &mut self.to_pass,
+            &mut self.to_db,
+        );
+
+        if let Some(v) = jobs {
+            self.dump_jobs = v;
+            self.restore_jobs = v;
+        }
+        set(&mut self.dump_jobs, dump_jobs);
+        set(&mut self.restore_jobs, restore_jobs);
+        set(&mut self.max_parallel, max_parallel);
+        set(&mut self.compression, compression);
+        set(&mut self.jobs_budget, jobs_budget.map(Some));
+        set(&mut self.order, order);
+        set(&mut self.priority_dbs, priority);
+        set(&mut self.dump_root, dump_root);
+        set(&mut self.state_root, state_dir.map(Some));
+        set(&mut self.run_id, run_id.map(Some));
+        set(&mut self.stale_dump_policy, stale_dump_policy);
+        set(&mut self.max_dump_age, max_dump_age.map(Some));
+        set(&mut self.migrate_globals, migrate_globals);
+        set(
+            &mut self.disable_dst_optimizations,
+            disable_dst_optimizations,
+        );
+        set(&mut self.fast_restore_profile, fast_restore_profile);
+        self.fast_restore_profiles.extend(fast_restore_profiles);
+        set(&mut self.restart_policy, restart_policy);
+        set(&mut self.stream, stream);
+        set(&mut self.update_extensions, update_extensions);
+        set(&mut self.analyze, analyze);
+        set(&mut self.reindex_collations, reindex_collations);
+        set(&mut self.ignore_incompatible, ignore_incompatible);
+        set(&mut self.include_dbs, include);
+        set(&mut self.exclude_dbs, exclude);
+        set(&mut self.include_postgres_db, include_postgres_db);
+        self.databases.extend(databases);
+        set(&mut self.exclude_roles, exclude_roles);
+        self.role_renames.extend(rename_roles);
+    }
+
+    /// Returns the number of `pg_dump` jobs to use for `db`.
+    #[must_use]
+    pub fn dump_jobs_for(&self, db: &str) -> usize {
+        self.databases
+            .get(db)
+            .and_then(|o| o.dump_jobs.or(o.jobs))
+            .unwrap_or(self.dump_jobs)
+    }
+
+    /// Returns the number of `pg_restore` jobs to use for `db`.
+    #[must_use]
+    pub fn restore_jobs_for(&self, db: &str) -> usize {
+        self.databases
+            .get(db)
+            .and_then(|o| o.restore_jobs.or(o.jobs))
+            .unwrap_or(self.restore_jobs)
+    }
+
+    /// Returns the `pg_dump` compression spec to use for `db`.
+    #[must_use]
+    pub fn compression_for(&self, db: &str) -> &str {
+        self.databases
+            .get(db)
+            .and_then(|o| o.compression.as_deref())
+            .unwrap_or(&self.compression)
+    }
+
+    /// Compiles the `--include-db`/`--exclude-db` patterns.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any pattern is invalid.
+    pub fn db_filter(&self) -> Result<NameFilter> {
+        NameFilter::new(&self.include_dbs, &self.exclude_dbs)
+    }
+
+    /// Compiles the `--exclude-role` patterns; a role passes the filter
+    /// unless it is excluded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any pattern is invalid.
+    pub fn role_filter(&self) -> Result<NameFilter> {
+        NameFilter::new(&[], &self.exclude_roles)
+    }
+
+    /// Checks the options that are only parsed when used: the filter patterns
+    /// and the fast restore profile.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a database or role filter pattern is invalid or
+    /// the fast restore profile is unknown.
+    pub fn validate(&self) -> Result<()> {
+        self.db_filter()?;
+        self.role_filter()?;
+        settings::profile(self)?;
+        Ok(())
+    }
+
+    /// Returns the name `db` gets on the target server.
+    #[must_use]
+    pub fn target_db<'a>(&'a self, db: &'a str) -> &'a str {
+        self.databases
+            .get(db)
+            .and_then(|o| o.target.as_deref())
+            .unwrap_or(db)
+    }
+
+    /// Returns the name of this run's state directory: the run ID if one was
+    /// given, otherwise a fingerprint of the source and target servers.
+    #[must_use]
+    pub fn run_id(&self) -> String {
+        self.run_id.clone().unwrap_or_else(|| {
+            let fingerprint = format!(
+                "{}_{}-{}_{}",
+                self.from_host, self.from_port, self.to_host, self.to_port
+            );
+            fingerprint
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// Returns the directory holding this run's state markers.
+    ///
+    /// Without `HOME` (e.g. in minimal containers) and without an explicit
+    /// state root, `pg_migrate_state` in the working directory is used.
+    #[must_use]
+    pub fn state_dir(&self) -> PathBuf {
+        let root = self
+            .state_root
+            .clone()
+            .unwrap_or_else(|| crate::home().unwrap_or_default().join("pg_migrate_state"));
+        root.join(self.run_id())
+    }
+
+    /// Returns true if `db` is marked as skipped in the config file.
+    #[must_use]
+    pub fn is_skipped(&self, db: &str) -> bool {
+        self.databases.get(db).is_some_and(|o| o.skip)
+    }
+}
+
+/// Overwrites `target` with `value` if the config file set it.
+fn set<T>(target: &mut T, value: Option<T>) {
+    if let Some(v) = value {
+        *target = v;
+    }
+}
+
+/// Top-level layout of a `migrate.toml` file.
+///
+/// Every field is optional; anything left out keeps its built-in default and
+/// can still be overridden by `PGMIGRATE_*` environment variables or CLI flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub source: EndpointFile,
+    pub target: EndpointFile,
+    pub jobs: Option<usize>,
+    pub dump_jobs: Option<usize>,
+    pub restore_jobs: Option<usize>,
+    pub max_parallel: Option<usize>,
+    pub compression: Option<String>,
+    pub jobs_budget: Option<usize>,
+    pub order: Option<ScheduleOrder>,
+    pub priority: Option<Vec<String>>,
+    pub dump_root: Option<PathBuf>,
+    pub state_dir: Option<PathBuf>,
+    pub run_id: Option<String>,
+    pub stale_dump_policy: Option<StaleDumpPolicy>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub max_dump_age: Option<Duration>,
+    pub migrate_globals: Option<bool>,
+    pub disable_dst_optimizations: Option<bool>,
+    pub fast_restore_profile: Option<String>,
+    #[serde(deserialize_with = "deserialize_profiles")]
+    pub fast_restore_profiles: BTreeMap<String, BTreeMap<String, String>>,
+    pub restart_policy: Option<RestartPolicy>,
+    pub stream: Option<bool>,
+    pub update_extensions: Option<bool>,
+    pub analyze: Option<bool>,
+    pub reindex_collations: Option<bool>,
+    pub ignore_incompatible: Option<bool>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub include_postgres_db: Option<bool>,
+    pub databases: BTreeMap<String, DatabaseOverride>,
+    pub exclude_roles: Option<Vec<String>>,
+    /// `[rename_roles]` table mapping source role names to target names.
+    pub rename_roles: BTreeMap<String, String>,
+}
+
+impl FileConfig {
+    /// Reads and parses a TOML config file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is not valid TOML.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let s = String::deserialize(d)?;
+    parse_duration(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Reads `[fast_restore_profiles.<name>]` tables, accepting strings, numbers
+/// and booleans as setting values.
+fn deserialize_profiles<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<BTreeMap<String, BTreeMap<String, String>>, D::Error> {
+    let profiles = BTreeMap::<String, BTreeMap<String, toml::Value>>::deserialize(d)?;
+    profiles
+        .into_iter()
+        .map(|(name, settings)| {
+            let settings = settings
+                .into_iter()
+                .map(|(setting, value)| {
+                    let value = match value {
+                        toml::Value::String(s) => s,
+                        toml::Value::Integer(i) => i.to_string(),
+                        toml::Value::Float(f) => f.to_string(),
+                        toml::Value::Boolean(b) => if b { "on" } else { "off" }.to_string(),
+                        other => {
+                            return Err(serde::de::Error::custom(format!(
+                                "invalid value for {setting} in profile {name}: {other}"
+                            )));
+                        }
+                    };
+                    Ok((setting, value))
+                })
+                .collect::<Result<_, _>>()?;
+            Ok((name, settings))
+        })
+        .collect()
+}
+
+/// Parses a duration such as `90s`, `30m`, `12h` or `7d`; plain numbers are seconds.
+///
+/// # Errors
+///
+/// Returns an error if the number or unit is invalid.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{s}'"))?;
+    let factor = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration unit in '{s}', expected s, m, h or d"
+            ));
+        }
+    };
+    Ok(Duration::from_secs(number.saturating_mul(factor)))
+}
+
+/// What to do with an existing dump that fails validation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StaleDumpPolicy {
+    /// Delete the dump and take a fresh one.
+    #[default]
+    Redump,
+    /// Stop with an error so an operator can decide.
+    Fail,
+    /// Keep using a dump that is merely old or from another source;
+    /// incomplete or unreadable dumps are still taken again.
+    Reuse,
+}
+
+impl FromStr for StaleDumpPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redump" => Ok(Self::Redump),
+            "fail" => Ok(Self::Fail),
+            "reuse" => Ok(Self::Reuse),
+            _ => Err(format!("expected redump, fail or reuse, got '{s}'")),
+        }
+    }
+}
+
+/// What to do with fast restore settings that need a server restart, such
+/// as `wal_level`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RestartPolicy {
+    /// Leave them out and warn.
+    #[default]
+    Skip,
+    /// Apply them and wait for an operator to restart the target.
+    Prompt,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "prompt" => Ok(Self::Prompt),
+            _ => Err(format!("expected skip or prompt, got '{s}'")),
+        }
+    }
+}
+
+/// Order in which databases are started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScheduleOrder {
+    /// Start the biggest databases first so they do not finish last on their own.
+    #[default]
+    LargestFirst,
+    SmallestFirst,
+}
+
+impl FromStr for ScheduleOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "largest-first" => Ok(Self::LargestFirst),
+            "smallest-first" => Ok(Self::SmallestFirst),
+            _ => Err(format!(
+                "expected largest-first or smallest-first, got '{s}'"
+            )),
+        }
+    }
+}
+
+/// Connection settings for the `[source]` and `[target]` tables.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointFile {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub db: Option<String>,
+}
+
+impl EndpointFile {
+    fn apply(
+        self,
+        host: &mut String,
+        port: &mut String,
+        user: &mut String,
+        pass: &mut String,
+        db: &mut String,
+    ) {
+        if let Some(v) = self.host {
+            *host = v;
+        }
+        if let Some(v) = self.port {
+            *port = v.to_string();
+        }
+        if let Some(v) = self.user {
+            *user = v;
+        }
+        if let Some(v) = self.pass {
+            *pass = v;
+        }
+        if let Some(v) = self.db {
+            *db = v;
+        }
+    }
+}
+
+/// Per-database settings from a `[databases.<name>]` table.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseOverride {
+    /// Name of the database on the target, if different from the source.
+    pub target: Option<String>,
+    pub jobs: Option<usize>,
+    pub compression: Option<String>,
+    pub skip: bool,
+    /// Share of the jobs budget for `pg_dump`; set by the scheduler, not
+    /// read from the file.
+    #[serde(skip)]
+    pub dump_jobs: Option<usize>,
+    /// Share of the jobs budget for `pg_restore`; set by the scheduler, not
+    /// read from the file.
+    #[serde(skip)]
+    pub restore_jobs: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_mins(30)));
+        assert_eq!(parse_duration(" 12 h "), Ok(Duration::from_hours(12)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_hours(7 * 24)));
+        assert_eq!(parse_duration("0d"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for s in ["", "d", "1w", "1.5h", "-1s", "1 d s", "1D"] {
+            assert!(parse_duration(s).is_err(), "{s:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn saturates_huge_durations() {
+        assert_eq!(
+            parse_duration(&format!("{}d", u64::MAX)),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+        assert!(parse_duration("18446744073709551616").is_err());
+    }
+}
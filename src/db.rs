@@ -1,21 +1,36 @@
 use crate::Config;
-use crate::state_dir;
+use crate::dumps::{self, DumpAction, DumpRecord};
+use crate::manifest::{self, GlobalsStatement, StatementOutcome};
+use crate::observer::{MigrationObserver, Phase};
+use crate::script::{self, ScriptItem};
+use crate::settings;
+use crate::{roles, schedule};
 use anyhow::{Context, Result};
-use indicatif::{HumanBytes, ProgressBar};
-use log::{info, warn};
+use log::info;
+use regex::Regex;
 use sqlx::{PgPool, Row, postgres::PgPoolOptions};
 use std::{
     fs,
     path::{Path, PathBuf},
+    process::Stdio,
+    sync::LazyLock,
 };
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::process::Command;
 use tokio::select;
 use tokio_util::sync::CancellationToken;
 
+/// Returns the directory holding the dump of `db`.
+#[must_use]
 pub fn dump_dir(root: &Path, db: &str) -> PathBuf {
     root.join(db)
 }
 
+/// Opens a small connection pool to the given server and database.
+///
+/// # Errors
+///
+/// Returns an error if the connection cannot be established.
 pub async fn pg_pool(host: &str, port: &str, user: &str, pass: &str, db: &str) -> Result<PgPool> {
     let url = format!("postgres://{user}:{pass}@{host}:{port}/{db}");
     let pool = PgPoolOptions::new()
@@ -25,6 +40,13 @@ pub async fn pg_pool(host: &str, port: &str, user: &str, pass: &str, db: &str) -
     Ok(pool)
 }
 
+/// Lists the source databases to migrate with their sizes in bytes, in the
+/// order they should be started (see [`schedule::order`]).
+///
+/// # Errors
+///
+/// Returns an error if the source cannot be queried, a filter or priority
+/// pattern is invalid, or two databases would be restored under the same name.
 pub async fn discover_databases(config: &Config) -> Result<Vec<(String, u64)>> {
     let pool = pg_pool(
         &config.from_host,
@@ -35,117 +57,230 @@ pub async fn discover_databases(config: &Config) -> Result<Vec<(String, u64)>> {
     )
     .await?;
 
+    let filter = config.db_filter()?;
+
     let rows = sqlx::query(
         "SELECT datname, pg_database_size(datname) AS size \
          FROM pg_database \
-         WHERE datname NOT IN ('postgres','template0','template1') \
+         WHERE datname NOT IN ('template0','template1') \
+         AND (datname <> 'postgres' OR $1) \
          AND datallowconn IS TRUE \
          ORDER BY pg_database_size(datname) ASC;",
     )
+    .bind(config.include_postgres_db)
     .fetch_all(&pool)
     .await?;
 
-    let mut dbs = Vec::with_capacity(rows.len());
+    let mut dbs: Vec<(String, u64)> = Vec::with_capacity(rows.len());
     for row in rows {
         let name: String = row.get(0);
         let size: i64 = row.get(1);
+        if !filter.matches(&name) {
+            info!("Skipping {name} (filtered out)");
+            continue;
+        }
+        if config.is_skipped(&name) {
+            info!("Skipping {name} (marked skip in config)");
+            continue;
+        }
+        let target = config.target_db(&name);
+        if let Some((other, _)) = dbs.iter().find(|(n, _)| config.target_db(n) == target) {
+            anyhow::bail!("{other} and {name} would both be restored into {target}");
+        }
         dbs.push((name, size.max(0).try_into().unwrap_or(0)));
     }
+    schedule::order(config, &mut dbs)?;
     Ok(dbs)
 }
 
-#[allow(dead_code)]
-pub async fn migrate_db(
-    config: &Config,
-    db: &str,
-    size: u64,
-    pb: ProgressBar,
-    cancel: CancellationToken,
-) -> Result<()> {
-    // Kept for backward compatibility: performs dump then restore
-    dump_db(config, db, size, pb.clone(), cancel.clone()).await?;
-    restore_db(config, db, size, pb, cancel).await
+/// Returns the total size in bytes of the files directly inside `dir`.
+///
+/// # Errors
+///
+/// Returns an error if the directory cannot be read.
+pub fn dir_size(dir: &Path) -> Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let meta = entry?.metadata()?;
+        if meta.is_file() {
+            total += meta.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Returns the `pg_dump` arguments used to dump `db` into `dump_path`.
+///
+/// # Panics
+///
+/// Panics if the dump path is not valid UTF-8.
+#[must_use]
+pub fn pg_dump_args(config: &Config, db: &str, dump_path: &Path) -> Vec<String> {
+    [
+        "-h",
+        &config.from_host,
+        "-p",
+        &config.from_port,
+        "-U",
+        &config.from_user,
+        "-Fd",
+        "-j",
+        &config.dump_jobs_for(db).to_string(),
+        "-Z",
+        config.compression_for(db),
+        "-f",
+        dump_path.to_str().expect("invalid dump path"),
+        db,
+    ]
+    .map(String::from)
+    .to_vec()
 }
 
-pub fn dump_done_marker(db: &str) -> PathBuf {
-    state_dir().join(format!("{db}.dumped"))
+/// Returns the `pg_restore` arguments used to restore `dump_path` into the
+/// target database of `db`.
+///
+/// With `clean` set, objects an earlier attempt left behind are dropped
+/// before they are created again.
+///
+/// # Panics
+///
+/// Panics if the dump path is not valid UTF-8.
+#[must_use]
+pub fn pg_restore_args(config: &Config, db: &str, dump_path: &Path, clean: bool) -> Vec<String> {
+    let mut args: Vec<String> = [
+        "-h",
+        &config.to_host,
+        "-p",
+        &config.to_port,
+        "-U",
+        &config.to_user,
+        "-j",
+        &config.restore_jobs_for(db).to_string(),
+        "--disable-triggers",
+    ]
+    .map(String::from)
+    .to_vec();
+    if clean {
+        args.extend(["--clean", "--if-exists"].map(String::from));
+    }
+    args.extend(
+        [
+            "-d",
+            config.target_db(db),
+            dump_path.to_str().expect("invalid dump path"),
+        ]
+        .map(String::from),
+    );
+    args
 }
 
+/// Dumps `db` from the source in directory format.
+///
+/// An existing dump is checked with [`dumps::check_dump`] and reused or taken
+/// again according to the stale dump policy.
+///
+/// # Errors
+///
+/// Returns an error if `pg_dump` fails or is cancelled, or if the existing
+/// dump is stale and the policy is to fail.
+///
+/// # Panics
+///
+/// Panics if the dump path is not valid UTF-8.
 pub async fn dump_db(
     config: &Config,
     db: &str,
     size: u64,
-    pb: ProgressBar,
+    observer: &dyn MigrationObserver,
     cancel: CancellationToken,
 ) -> Result<()> {
-    let mut bar_total = size.saturating_mul(2);
-    if bar_total == 0 {
-        bar_total = 100;
+    let dump_path = dump_dir(&config.dump_root, db);
+
+    if dump_path.join("toc.dat").exists() {
+        let problem = dumps::check_dump(config, db, &dump_path).await?;
+        if dumps::decide(config, db, problem.as_ref())? == DumpAction::Reuse {
+            if let Some(problem) = problem {
+                observer.warning(Some(db), &format!("reusing a dump that {problem}"));
+            }
+            observer.phase_skipped(db, Phase::Dump);
+            return Ok(());
+        }
+        if let Some(problem) = problem {
+            observer.warning(
+                Some(db),
+                &format!("dumping again, the existing dump {problem}"),
+            );
+        }
     }
-    let phase_mid = bar_total / 2;
-    let human_size = HumanBytes(size);
+    if dump_path.exists() {
+        fs::remove_dir_all(&dump_path)
+            .with_context(|| format!("failed to remove {}", dump_path.display()))?;
+        // The restore and the row counts came from the old dump
+        manifest::dump_replaced(config, db)?;
+    }
+    fs::create_dir_all(&dump_path)?;
 
-    pb.set_length(bar_total);
-    pb.set_message(format!("Dumping {db} ({human_size})"));
+    observer.phase_started(db, Phase::Dump, size);
+    manifest::phase_started(config, db, Phase::Dump)?;
 
-    let dump_path = dump_dir(&config.dump_root, db);
-    fs::create_dir_all(&dump_path)?;
+    let mut result = run_pg_dump(config, db, &dump_path, cancel).await;
+    if result.is_ok() {
+        result = DumpRecord::new(config, db).await.write(&dump_path);
+    }
+    manifest::phase_finished(config, db, Phase::Dump, &result)?;
+    result?;
 
-    if !dump_path.join("toc.dat").exists() {
-        pb.set_message(format!("Dumping {db} ({human_size})"));
+    let bytes = dir_size(&dump_path)?;
+    manifest::update_database(config, db, |r| r.dump_bytes = Some(bytes))?;
+    observer.bytes(db, Phase::Dump, bytes);
+    observer.phase_finished(db, Phase::Dump);
+    Ok(())
+}
 
-        let mut child = Command::new("pg_dump")
-            .env("PGPASSWORD", &config.from_pass)
-            .args([
-                "-h",
-                &config.from_host,
-                "-p",
-                &config.from_port,
-                "-U",
-                &config.from_user,
-                "-Fd",
-                "-j",
-                &config.dump_jobs.to_string(),
-                "-Z",
-                "zstd:5",
-                "-f",
-                dump_path.to_str().expect("invalid dump path"),
-                db,
-            ])
-            .spawn() // spawn, don't block
-            .context("pg_dump failed to start")?;
-
-        let status = select! {
-            res = child.wait() => res.context("pg_dump wait failed")?,
-            () = cancel.cancelled() => {
-                let _ = child.kill().await;
-                anyhow::bail!("cancelled during pg_dump of {db}");
-            }
-        };
+async fn run_pg_dump(
+    config: &Config,
+    db: &str,
+    dump_path: &Path,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let mut child = Command::new("pg_dump")
+        .env("PGPASSWORD", &config.from_pass)
+        .args(pg_dump_args(config, db, dump_path))
+        .kill_on_drop(true)
+        .spawn() // spawn, don't block
+        .context("pg_dump failed to start")?;
 
-        if !status.success() {
-            anyhow::bail!("pg_dump failed for {db}");
+    let status = select! {
+        res = child.wait() => res.context("pg_dump wait failed")?,
+        () = cancel.cancelled() => {
+            let _ = child.kill().await;
+            anyhow::bail!("cancelled during pg_dump of {db}");
         }
-    }
+    };
 
-    pb.set_position(phase_mid);
-    fs::write(dump_done_marker(db), "")?;
+    if !status.success() {
+        anyhow::bail!("pg_dump failed for {db}");
+    }
     Ok(())
 }
 
+/// Restores the dump of `db` into its target database.
+///
+/// # Errors
+///
+/// Returns an error if the dump is missing or `pg_restore` fails or is cancelled.
+///
+/// # Panics
+///
+/// Panics if the dump path is not valid UTF-8.
 pub async fn restore_db(
     config: &Config,
     db: &str,
     size: u64,
-    pb: ProgressBar,
+    observer: &dyn MigrationObserver,
     cancel: CancellationToken,
 ) -> Result<()> {
-    let bar_total = size.saturating_mul(2);
-    let phase_end = bar_total;
-    let human_size = HumanBytes(size);
-
-    pb.set_length(bar_total);
-    pb.set_message(format!("Restoring {db} ({human_size})"));
+    observer.phase_started(db, Phase::Restore, size);
 
     let dump_path = dump_dir(&config.dump_root, db);
     fs::create_dir_all(&dump_path)?;
@@ -153,23 +288,37 @@ pub async fn restore_db(
     if !dump_path.join("toc.dat").exists() {
         anyhow::bail!("Dump not found for {db} at {}", dump_path.display());
     }
+    if DumpRecord::load(&dump_path).is_none() {
+        anyhow::bail!(
+            "Dump of {db} at {} is incomplete, dump it again",
+            dump_path.display()
+        );
+    }
+
+    roles::create_placeholders(config).await?;
+    let clean = manifest::load(config)?.restore_attempted(config, db);
+    manifest::phase_started(config, db, Phase::Restore)?;
+    let result = run_pg_restore(config, db, &dump_path, clean, cancel).await;
+    manifest::phase_finished(config, db, Phase::Restore, &result)?;
+    result?;
+
+    observer.bytes(db, Phase::Restore, size);
+    observer.phase_finished(db, Phase::Restore);
+    Ok(())
+}
 
+async fn run_pg_restore(
+    config: &Config,
+    db: &str,
+    dump_path: &Path,
+    clean: bool,
+    cancel: CancellationToken,
+) -> Result<()> {
     let mut child = Command::new("pg_restore")
         .env("PGPASSWORD", &config.to_pass)
-        .args([
-            "-h",
-            &config.to_host,
-            "-p",
-            &config.to_port,
-            "-U",
-            &config.to_user,
-            "-j",
-            &config.restore_jobs.to_string(),
-            "--disable-triggers",
-            "-d",
-            db,
-            dump_path.to_str().expect("invalid dump path"),
-        ])
+        .envs(settings::session_options(config)?.map(|o| ("PGOPTIONS", o)))
+        .args(pg_restore_args(config, db, dump_path, clean))
+        .kill_on_drop(true)
         .spawn()
         .context("pg_restore failed to start")?;
 
@@ -184,65 +333,259 @@ pub async fn restore_db(
     if !status.success() {
         anyhow::bail!("pg_restore failed for {db}");
     }
-
-    pb.set_position(phase_end);
-    pb.finish_with_message(format!("{db} ({human_size}) restored"));
-    fs::write(done_marker(db), "")?;
     Ok(())
 }
 
-pub async fn enable_fast_restore(config: &Config) -> Result<()> {
-    let settings = [
-        ("fsync", "off"),
-        ("synchronous_commit", "off"),
-        ("full_page_writes", "off"),
-        ("maintenance_work_mem", "'2GB'"),
-        ("checkpoint_completion_target", "0.9"),
-    ];
-
-    let pool = pg_pool(
+/// Returns the `vacuumdb` arguments used to analyze the target database of `db`.
+///
+/// `--analyze-in-stages` produces rough statistics quickly and refines them
+/// in two more passes, so the database is usable early.
+#[must_use]
+pub fn vacuumdb_args(config: &Config, db: &str) -> Vec<String> {
+    [
+        "-h",
         &config.to_host,
+        "-p",
         &config.to_port,
+        "-U",
         &config.to_user,
-        &config.to_pass,
-        &config.to_db,
-    )
-    .await?;
+        "-j",
+        &config.restore_jobs_for(db).to_string(),
+        "--analyze-in-stages",
+        "-d",
+        config.target_db(db),
+    ]
+    .map(String::from)
+    .to_vec()
+}
 
-    for (k, v) in settings {
-        let sql = format!("ALTER SYSTEM SET {k} TO {v};");
-        sqlx::query(&sql).execute(&pool).await?;
-    }
+/// Builds planner statistics for the restored `db`, which `pg_restore` does
+/// not carry over.
+///
+/// # Errors
+///
+/// Returns an error if `vacuumdb` fails or is cancelled.
+pub async fn analyze_db(
+    config: &Config,
+    db: &str,
+    observer: &dyn MigrationObserver,
+    cancel: CancellationToken,
+) -> Result<()> {
+    observer.phase_started(db, Phase::Analyze, 0);
+    manifest::phase_started(config, db, Phase::Analyze)?;
 
-    sqlx::query("SELECT pg_reload_conf();")
-        .execute(&pool)
-        .await?;
+    let result = run_vacuumdb(config, db, cancel).await;
+    manifest::phase_finished(config, db, Phase::Analyze, &result)?;
+    result?;
+
+    observer.phase_finished(db, Phase::Analyze);
     Ok(())
 }
 
-pub async fn restore_safe_settings(config: &Config) -> Result<()> {
-    let settings = ["fsync", "synchronous_commit", "full_page_writes"];
+async fn run_vacuumdb(config: &Config, db: &str, cancel: CancellationToken) -> Result<()> {
+    let mut child = Command::new("vacuumdb")
+        .env("PGPASSWORD", &config.to_pass)
+        .args(vacuumdb_args(config, db))
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("vacuumdb failed to start")?;
 
-    let pool = pg_pool(
+    let status = select! {
+        res = child.wait() => res.context("vacuumdb wait failed")?,
+        () = cancel.cancelled() => {
+            let _ = child.kill().await;
+            anyhow::bail!("cancelled during vacuumdb of {db}");
+        }
+    };
+
+    if !status.success() {
+        anyhow::bail!("vacuumdb failed for {db}");
+    }
+    Ok(())
+}
+
+/// Returns the `pg_dump` arguments used to stream `db` to stdout.
+///
+/// The custom format is used because the directory format cannot be written
+/// to a pipe; compression is off since the stream never touches disk.
+#[must_use]
+pub fn pg_dump_stream_args(config: &Config, db: &str) -> Vec<String> {
+    [
+        "-h",
+        &config.from_host,
+        "-p",
+        &config.from_port,
+        "-U",
+        &config.from_user,
+        "-Fc",
+        "-Z",
+        "0",
+        db,
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// Returns the `pg_restore` arguments used to restore `db` from stdin.
+///
+/// `-j` is left out: parallel restore needs a seekable archive. With `clean`
+/// set, objects an earlier attempt left behind are dropped before they are
+/// created again.
+#[must_use]
+pub fn pg_restore_stream_args(config: &Config, db: &str, clean: bool) -> Vec<String> {
+    let mut args: Vec<String> = [
+        "-h",
         &config.to_host,
+        "-p",
         &config.to_port,
+        "-U",
         &config.to_user,
-        &config.to_pass,
-        &config.to_db,
-    )
-    .await?;
-
-    for s in settings {
-        let sql = format!("ALTER SYSTEM RESET {s};");
-        sqlx::query(&sql).execute(&pool).await?;
+        "--disable-triggers",
+    ]
+    .map(String::from)
+    .to_vec();
+    if clean {
+        args.extend(["--clean", "--if-exists"].map(String::from));
     }
-    sqlx::query("SELECT pg_reload_conf();")
-        .execute(&pool)
-        .await?;
+    args.extend(["-d", config.target_db(db)].map(String::from));
+    args
+}
+
+/// Pipes `pg_dump` of `db` straight into `pg_restore`, without a local dump.
+///
+/// # Errors
+///
+/// Returns an error naming the side that failed, or a cancellation error.
+pub async fn stream_db(
+    config: &Config,
+    db: &str,
+    size: u64,
+    observer: &dyn MigrationObserver,
+    cancel: CancellationToken,
+) -> Result<()> {
+    observer.phase_started(db, Phase::Restore, size);
+    roles::create_placeholders(config).await?;
+    let clean = manifest::load(config)?.restore_attempted(config, db);
+    manifest::phase_started(config, db, Phase::Dump)?;
+    manifest::phase_started(config, db, Phase::Restore)?;
+
+    let mut streamed = 0;
+    let result = run_stream(config, db, clean, observer, &cancel, &mut streamed).await;
+    manifest::phase_finished(config, db, Phase::Dump, &result)?;
+    manifest::phase_finished(config, db, Phase::Restore, &result)?;
+    result?;
+
+    manifest::update_database(config, db, |r| r.dump_bytes = Some(streamed))?;
+    observer.phase_finished(db, Phase::Restore);
     Ok(())
 }
 
-pub async fn create_dbs(config: &Config, dbs: &[String]) -> Result<()> {
+/// Which end of the pipe broke while streaming.
+enum PipeError {
+    Read(std::io::Error),
+    Write(std::io::Error),
+}
+
+async fn run_stream(
+    config: &Config,
+    db: &str,
+    clean: bool,
+    observer: &dyn MigrationObserver,
+    cancel: &CancellationToken,
+    streamed: &mut u64,
+) -> Result<()> {
+    let mut dump = Command::new("pg_dump")
+        .env("PGPASSWORD", &config.from_pass)
+        .args(pg_dump_stream_args(config, db))
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("pg_dump failed to start")?;
+    let mut restore = Command::new("pg_restore")
+        .env("PGPASSWORD", &config.to_pass)
+        .envs(settings::session_options(config)?.map(|o| ("PGOPTIONS", o)))
+        .args(pg_restore_stream_args(config, db, clean))
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("pg_restore failed to start")?;
+
+    let mut reader = dump.stdout.take().context("pg_dump stdout not captured")?;
+    let mut writer = restore
+        .stdin
+        .take()
+        .context("pg_restore stdin not captured")?;
+
+    let piped = select! {
+        res = async {
+            let mut buf = vec![0; 1 << 16];
+            loop {
+                let n = reader.read(&mut buf).await.map_err(PipeError::Read)?;
+                if n == 0 {
+                    return writer.shutdown().await.map_err(PipeError::Write);
+                }
+                writer.write_all(&buf[..n]).await.map_err(PipeError::Write)?;
+                *streamed += n as u64;
+                observer.bytes(db, Phase::Restore, *streamed);
+            }
+        } => res,
+        () = cancel.cancelled() => {
+            let _ = dump.kill().await;
+            let _ = restore.kill().await;
+            anyhow::bail!("cancelled while streaming {db}");
+        }
+    };
+    // Closing stdin lets pg_restore see the end of the archive.
+    drop(writer);
+
+    match piped {
+        Ok(()) => {}
+        Err(PipeError::Read(e)) => {
+            let _ = restore.kill().await;
+            let status = dump.wait().await.context("pg_dump wait failed")?;
+            anyhow::bail!("pg_dump failed for {db} ({status}): reading its output failed: {e}");
+        }
+        Err(PipeError::Write(e)) => {
+            let _ = dump.kill().await;
+            let status = restore.wait().await.context("pg_restore wait failed")?;
+            anyhow::bail!(
+                "pg_restore failed for {db} ({status}): writing to it failed: {e}; pg_dump was stopped"
+            );
+        }
+    }
+
+    let (dump_status, restore_status) = select! {
+        res = async { tokio::try_join!(dump.wait(), restore.wait()) } => {
+            res.context("pg_dump/pg_restore wait failed")?
+        }
+        () = cancel.cancelled() => {
+            let _ = dump.kill().await;
+            let _ = restore.kill().await;
+            anyhow::bail!("cancelled while streaming {db}");
+        }
+    };
+
+    match (dump_status.success(), restore_status.success()) {
+        (true, true) => Ok(()),
+        (false, true) => anyhow::bail!("pg_dump failed for {db} ({dump_status})"),
+        (false, false) => anyhow::bail!(
+            "pg_dump failed for {db} ({dump_status}); pg_restore stopped on the incomplete archive"
+        ),
+        (true, false) => anyhow::bail!("pg_restore failed for {db} ({restore_status})"),
+    }
+}
+
+/// Creates the target databases; existing ones are left alone.
+///
+/// # Errors
+///
+/// Returns an error if the target cannot be reached.
+pub async fn create_dbs(
+    config: &Config,
+    dbs: &[String],
+    observer: &dyn MigrationObserver,
+) -> Result<()> {
     let pool = pg_pool(
         &config.to_host,
         &config.to_port,
@@ -253,33 +596,76 @@ pub async fn create_dbs(config: &Config, dbs: &[String]) -> Result<()> {
     .await?;
 
     for db in dbs {
-        let sql = format!("CREATE DATABASE \"{db}\"");
+        let target_db = config.target_db(db);
+        let sql = format!("CREATE DATABASE \"{target_db}\"");
         if let Err(e) = sqlx::query(&sql).execute(&pool).await {
-            warn!("Warning: CREATE DATABASE \"{db}\" failed or already exists: {e}");
+            observer.warning(
+                Some(db),
+                &format!("CREATE DATABASE \"{target_db}\" failed or already exists: {e}"),
+            );
         }
     }
     Ok(())
 }
 
-pub fn done_marker(db: &str) -> PathBuf {
-    state_dir().join(format!("{db}.done"))
+/// Returns the `server_version` of the source.
+///
+/// # Errors
+///
+/// Returns an error if the source cannot be queried.
+pub async fn source_server_version(config: &Config) -> Result<String> {
+    let pool = pg_pool(
+        &config.from_host,
+        &config.from_port,
+        &config.from_user,
+        &config.from_pass,
+        &config.from_db,
+    )
+    .await?;
+    Ok(sqlx::query_scalar("SHOW server_version")
+        .fetch_one(&pool)
+        .await?)
 }
 
-pub fn globals_marker() -> PathBuf {
-    state_dir().join("globals.done")
+/// Returns the `system_identifier` of the source cluster.
+///
+/// `None` if it cannot be read, e.g. because the user lacks the privilege to
+/// call `pg_control_system()`.
+pub async fn source_system_identifier(config: &Config) -> Option<String> {
+    let pool = pg_pool(
+        &config.from_host,
+        &config.from_port,
+        &config.from_user,
+        &config.from_pass,
+        &config.from_db,
+    )
+    .await
+    .ok()?;
+    sqlx::query_scalar("SELECT system_identifier::text FROM pg_control_system()")
+        .fetch_one(&pool)
+        .await
+        .ok()
 }
 
-pub async fn migrate_globals(config: &Config) -> Result<()> {
-    if globals_marker().exists() {
-        return Ok(());
+/// Returns the first line of `program --version`, or `None` if it cannot be run.
+pub async fn tool_version(program: &str) -> Option<String> {
+    let output = Command::new(program).arg("--version").output().await.ok()?;
+    if !output.status.success() {
+        return None;
     }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(|l| l.trim().to_string())
+}
 
-    info!("Migrating global objects...");
-
-    let globals_path = config.dump_root.join("globals.sql");
-    fs::create_dir_all(&config.dump_root)?;
-
-    let status = Command::new("pg_dumpall")
+/// Runs `pg_dumpall --globals-only` against the source and returns its output.
+///
+/// # Errors
+///
+/// Returns an error if `pg_dumpall` cannot be started or fails.
+pub async fn dump_globals(config: &Config) -> Result<String> {
+    let output = Command::new("pg_dumpall")
         .env("PGPASSWORD", &config.from_pass)
         .args([
             "-h",
@@ -289,42 +675,58 @@ pub async fn migrate_globals(config: &Config) -> Result<()> {
             "-U",
             &config.from_user,
             "--globals-only",
-            "-f",
-            globals_path.to_str().expect("invalid globals path"),
         ])
-        .status()
+        .stderr(Stdio::inherit())
+        .output()
         .await
         .context("pg_dumpall --globals-only failed")?;
 
-    if !status.success() {
+    if !output.status.success() {
         anyhow::bail!("pg_dumpall failed");
     }
+    String::from_utf8(output.stdout).context("pg_dumpall output is not valid UTF-8")
+}
 
-    let globals_content = fs::read_to_string(&globals_path)?;
-    let mut filtered_content = Vec::new();
-    for line in globals_content.lines() {
-        if (line.starts_with("CREATE ROLE ") || line.starts_with("ALTER ROLE "))
-            && line.contains(&format!(" {} ", config.to_user))
-        {
-            info!(
-                "Skipping migration of role '{}' to avoid password overwrite.",
-                config.to_user
-            );
-            continue;
-        }
-        if (line.starts_with("CREATE ROLE ") || line.starts_with("ALTER ROLE "))
-            && line.ends_with(&format!(" {};", config.to_user))
-        {
-            info!(
-                "Skipping migration of role '{}' to avoid password overwrite.",
-                config.to_user
-            );
-            continue;
-        }
+/// Masks the password literals of a role statement.
+#[must_use]
+pub fn redact_passwords(statement: &str) -> String {
+    static PASSWORD: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?i)\bPASSWORD\s+'(?:[^']|'')*'").expect("password pattern is valid")
+    });
+    PASSWORD
+        .replace_all(statement, "PASSWORD '********'")
+        .into_owned()
+}
 
-        filtered_content.push(line);
+/// Copies roles and other global objects from source to target.
+///
+/// The migration user's own role is skipped so its password on the target is
+/// not overwritten.
+///
+/// # Errors
+///
+/// Returns an error if `pg_dumpall` fails or the target cannot be reached.
+pub async fn migrate_globals(config: &Config, observer: &dyn MigrationObserver) -> Result<()> {
+    if manifest::load(config)?.globals_finished() {
+        return Ok(());
     }
-    fs::write(&globals_path, filtered_content.join("\n"))?;
+
+    info!("Migrating global objects...");
+    let started_at = manifest::now();
+    let result = copy_globals(config, observer).await;
+    manifest::globals_finished(config, started_at, &result)?;
+    result
+}
+
+async fn copy_globals(config: &Config, observer: &dyn MigrationObserver) -> Result<()> {
+    let globals_path = config.dump_root.join("globals.sql");
+    fs::create_dir_all(&config.dump_root)?;
+
+    let globals_content = dump_globals(config).await?;
+    fs::write(
+        &globals_path,
+        roles::filter_globals(config, &globals_content)?,
+    )?;
 
     let pool = pg_pool(
         &config.to_host,
@@ -336,24 +738,129 @@ pub async fn migrate_globals(config: &Config) -> Result<()> {
     .await?;
 
     let sql = fs::read_to_string(&globals_path)?;
-    for stmt in sql.split(";\n") {
-        let s = stmt.trim();
-        if s.is_empty() {
-            continue;
-        }
-        let exec_sql = format!("{s};");
-        if let Err(e) = sqlx::query(&exec_sql).execute(&pool).await {
-            let msg = format!("{e}");
-            if msg.contains("already exists")
-                || msg.contains("MD5-encrypted password")
-                || msg.contains("MD5 password support is deprecated")
-            {
-                continue;
-            }
-            warn!("Warning: executing globals statement failed: {msg}");
+    // Statements such as `SET` apply to the session, so they all share one
+    let mut conn = pool.acquire().await?;
+    let mut records = Vec::new();
+    for item in script::split(&sql) {
+        let (outcome, detail) = match &item {
+            ScriptItem::Meta(_) => (
+                StatementOutcome::Skipped,
+                Some("psql meta-command".to_string()),
+            ),
+            ScriptItem::Sql(statement) => match sqlx::query(statement).execute(&mut *conn).await {
+                Ok(_) => (StatementOutcome::Applied, None),
+                Err(e) if is_duplicate_object(&e) => (
+                    StatementOutcome::Skipped,
+                    Some("already exists".to_string()),
+                ),
+                Err(e) => {
+                    observer.warning(None, &format!("executing globals statement failed: {e}"));
+                    (StatementOutcome::Failed, Some(e.to_string()))
+                }
+            },
+        };
+        records.push(GlobalsStatement {
+            statement: redact_passwords(item.text()),
+            outcome,
+            detail,
+        });
+    }
+    manifest::update(config, |m| m.globals_statements = records)
+}
+
+/// Returns true if `e` reports that the object to create already exists.
+fn is_duplicate_object(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .and_then(sqlx::error::DatabaseError::code)
+        .is_some_and(|code| code == "42710")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            to_host: "target".to_string(),
+            to_port: "5433".to_string(),
+            to_user: "postgres".to_string(),
+            restore_jobs: 4,
+            ..Config::default()
         }
     }
 
-    fs::write(globals_marker(), "")?;
-    Ok(())
+    #[test]
+    fn restore_args_clean_only_after_an_attempt() {
+        let path = Path::new("/dumps/app");
+        assert_eq!(
+            pg_restore_args(&config(), "app", path, false),
+            [
+                "-h",
+                "target",
+                "-p",
+                "5433",
+                "-U",
+                "postgres",
+                "-j",
+                "4",
+                "--disable-triggers",
+                "-d",
+                "app",
+                "/dumps/app"
+            ]
+        );
+        assert_eq!(
+            pg_restore_args(&config(), "app", path, true),
+            [
+                "-h",
+                "target",
+                "-p",
+                "5433",
+                "-U",
+                "postgres",
+                "-j",
+                "4",
+                "--disable-triggers",
+                "--clean",
+                "--if-exists",
+                "-d",
+                "app",
+                "/dumps/app"
+            ]
+        );
+    }
+
+    #[test]
+    fn stream_restore_args_clean_only_after_an_attempt() {
+        assert_eq!(
+            pg_restore_stream_args(&config(), "app", false),
+            [
+                "-h",
+                "target",
+                "-p",
+                "5433",
+                "-U",
+                "postgres",
+                "--disable-triggers",
+                "-d",
+                "app"
+            ]
+        );
+        assert_eq!(
+            pg_restore_stream_args(&config(), "app", true),
+            [
+                "-h",
+                "target",
+                "-p",
+                "5433",
+                "-U",
+                "postgres",
+                "--disable-triggers",
+                "--clean",
+                "--if-exists",
+                "-d",
+                "app"
+            ]
+        );
+    }
 }
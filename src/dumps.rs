@@ -0,0 +1,186 @@
+use crate::config::StaleDumpPolicy;
+use crate::{Config, db, manifest};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+use tokio::process::Command;
+
+/// Completion record written into a dump directory once `pg_dump` succeeded.
+///
+/// A dump directory without one is the leftover of an interrupted run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DumpRecord {
+    /// Source database name.
+    pub database: String,
+    /// Source server as `host:port`.
+    pub source: String,
+    /// `system_identifier` of the source cluster, if it could be read.
+    pub system_identifier: Option<String>,
+    pub server_version: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub finished_at: u64,
+}
+
+impl DumpRecord {
+    /// Describes the dump of `db` that has just been taken from the source.
+    pub async fn new(config: &Config, db: &str) -> Self {
+        Self {
+            database: db.to_string(),
+            source: source(config),
+            system_identifier: db::source_system_identifier(config).await,
+            server_version: db::source_server_version(config).await.ok(),
+            finished_at: manifest::now(),
+        }
+    }
+
+    /// Reads the completion record of the dump in `dump_path`, if any.
+    #[must_use]
+    pub fn load(dump_path: &Path) -> Option<Self> {
+        let content = fs::read_to_string(record_path(dump_path)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Writes the record into `dump_path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be written.
+    pub fn write(&self, dump_path: &Path) -> Result<()> {
+        let path = record_path(dump_path);
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+/// Returns where the completion record of the dump in `dump_path` is stored.
+#[must_use]
+pub fn record_path(dump_path: &Path) -> PathBuf {
+    dump_path.join("pg_migrate_dump.json")
+}
+
+fn source(config: &Config) -> String {
+    format!("{}:{}", config.from_host, config.from_port)
+}
+
+/// Why an existing dump should not be reused as is.
+#[derive(Clone, Debug)]
+pub enum DumpProblem {
+    /// There is no completion record, so `pg_dump` never finished.
+    Incomplete,
+    /// `pg_restore -l` could not read the dump.
+    Unreadable(String),
+    /// The dump was taken from another server or database.
+    OtherSource { recorded: String, current: String },
+    /// The dump is older than the configured maximum age.
+    TooOld { age: Duration, max: Duration },
+}
+
+impl DumpProblem {
+    /// Returns true if the dump cannot be restored at all, whatever the policy.
+    #[must_use]
+    pub const fn is_unusable(&self) -> bool {
+        matches!(self, Self::Incomplete | Self::Unreadable(_))
+    }
+}
+
+impl fmt::Display for DumpProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Incomplete => write!(f, "has no completion record (interrupted pg_dump?)"),
+            Self::Unreadable(e) => write!(f, "is not readable by pg_restore: {e}"),
+            Self::OtherSource { recorded, current } => {
+                write!(f, "was taken from {recorded}, not {current}")
+            }
+            Self::TooOld { age, max } => write!(
+                f,
+                "is {} old, more than the allowed {}",
+                indicatif::HumanDuration(*age),
+                indicatif::HumanDuration(*max)
+            ),
+        }
+    }
+}
+
+/// Checks an existing dump of `db` before it is reused.
+///
+/// Returns `None` if the dump is complete, readable, taken from the current
+/// source and recent enough.
+///
+/// # Errors
+///
+/// Returns an error if `pg_restore` cannot be started.
+pub async fn check_dump(
+    config: &Config,
+    db: &str,
+    dump_path: &Path,
+) -> Result<Option<DumpProblem>> {
+    let Some(record) = DumpRecord::load(dump_path) else {
+        return Ok(Some(DumpProblem::Incomplete));
+    };
+
+    let output = Command::new("pg_restore")
+        .arg("-l")
+        .arg(dump_path)
+        .stdout(Stdio::null())
+        .output()
+        .await
+        .context("pg_restore failed to start")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Ok(Some(DumpProblem::Unreadable(stderr.trim().to_string())));
+    }
+
+    let current_id = db::source_system_identifier(config).await;
+    let (recorded, current) = match (&record.system_identifier, current_id) {
+        (Some(recorded), Some(current)) => (
+            format!("cluster {recorded}/{}", record.database),
+            format!("cluster {current}/{db}"),
+        ),
+        _ => (
+            format!("{}/{}", record.source, record.database),
+            format!("{}/{db}", source(config)),
+        ),
+    };
+    if recorded != current {
+        return Ok(Some(DumpProblem::OtherSource { recorded, current }));
+    }
+
+    if let Some(max) = config.max_dump_age {
+        let age = Duration::from_secs(manifest::now().saturating_sub(record.finished_at));
+        if age > max {
+            return Ok(Some(DumpProblem::TooOld { age, max }));
+        }
+    }
+    Ok(None)
+}
+
+/// What to do with an existing dump after [`check_dump`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpAction {
+    Reuse,
+    Redump,
+}
+
+/// Decides whether a dump with `problem` is reused or taken again.
+///
+/// # Errors
+///
+/// Returns an error if the policy is [`StaleDumpPolicy::Fail`] and the dump
+/// has a problem.
+pub fn decide(config: &Config, db: &str, problem: Option<&DumpProblem>) -> Result<DumpAction> {
+    let Some(problem) = problem else {
+        return Ok(DumpAction::Reuse);
+    };
+    match config.stale_dump_policy {
+        StaleDumpPolicy::Fail => anyhow::bail!(
+            "existing dump of {db} {problem}; remove it or use --stale-dump-policy redump"
+        ),
+        StaleDumpPolicy::Reuse if !problem.is_unusable() => Ok(DumpAction::Reuse),
+        StaleDumpPolicy::Reuse | StaleDumpPolicy::Redump => Ok(DumpAction::Redump),
+    }
+}
@@ -0,0 +1,284 @@
+use crate::manifest::ExtensionUpdate;
+use crate::observer::{MigrationObserver, Phase};
+use crate::{Config, db, manifest};
+use anyhow::Result;
+use sqlx::PgPool;
+use std::{collections::BTreeMap, fmt};
+
+/// Extensions whose library must be listed in `shared_preload_libraries`,
+/// and whether `CREATE EXTENSION` itself fails without it.
+const PRELOAD_LIBRARIES: &[(&str, bool)] = &[
+    ("citus", true),
+    ("pg_cron", true),
+    ("pgaudit", true),
+    ("timescaledb", true),
+    ("pg_qualstats", false),
+    ("pg_squeeze", false),
+    ("pg_stat_kcache", false),
+    ("pg_stat_statements", false),
+    ("pg_wait_sampling", false),
+    ("pglogical", false),
+];
+
+/// Why an extension of a source database may not come over as is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExtensionProblem {
+    /// The target does not provide the extension at all.
+    Missing,
+    /// The target creates the extension at another version.
+    VersionDiffers { target: String },
+    /// The extension needs to be preloaded and the target does not preload it.
+    NotPreloaded { blocks_create: bool },
+}
+
+/// One extension of a source database that needs attention.
+#[derive(Clone, Debug)]
+pub struct ExtensionIssue {
+    pub extension: String,
+    pub source_version: String,
+    pub problem: ExtensionProblem,
+}
+
+impl ExtensionIssue {
+    /// Returns true if `pg_restore` will fail to create the extension.
+    #[must_use]
+    pub const fn is_fatal(&self) -> bool {
+        matches!(
+            self.problem,
+            ExtensionProblem::Missing
+                | ExtensionProblem::NotPreloaded {
+                    blocks_create: true
+                }
+        )
+    }
+}
+
+impl fmt::Display for ExtensionProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "not available on the target"),
+            Self::VersionDiffers { target } => write!(f, "will be created at version {target}"),
+            Self::NotPreloaded {
+                blocks_create: true,
+            } => write!(f, "cannot be created without shared_preload_libraries"),
+            Self::NotPreloaded {
+                blocks_create: false,
+            } => write!(f, "does not work until added to shared_preload_libraries"),
+        }
+    }
+}
+
+/// What the target server can offer to restored databases.
+pub struct TargetExtensions {
+    /// Default version of every available extension.
+    available: BTreeMap<String, String>,
+    preloaded: Vec<String>,
+}
+
+impl TargetExtensions {
+    /// Reads the available extensions and preloaded libraries of the target.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the target cannot be queried.
+    pub async fn load(config: &Config) -> Result<Self> {
+        let pool = db::pg_pool(
+            &config.to_host,
+            &config.to_port,
+            &config.to_user,
+            &config.to_pass,
+            &config.to_db,
+        )
+        .await?;
+        let available: Vec<(String, Option<String>)> =
+            sqlx::query_as("SELECT name, default_version FROM pg_available_extensions")
+                .fetch_all(&pool)
+                .await?;
+        let preload: String = sqlx::query_scalar("SHOW shared_preload_libraries")
+            .fetch_one(&pool)
+            .await?;
+        Ok(Self {
+            available: available
+                .into_iter()
+                .map(|(name, version)| (name, version.unwrap_or_default()))
+                .collect(),
+            preloaded: preload
+                .split(',')
+                .map(|l| l.trim().trim_matches('"').to_string())
+                .filter(|l| !l.is_empty())
+                .collect(),
+        })
+    }
+
+    fn issue(&self, extension: String, source_version: String) -> Option<ExtensionIssue> {
+        let problem = match self.available.get(&extension) {
+            None => ExtensionProblem::Missing,
+            Some(_) if !self.is_preloaded(&extension) => ExtensionProblem::NotPreloaded {
+                blocks_create: PRELOAD_LIBRARIES
+                    .iter()
+                    .any(|(name, blocks)| *name == extension && *blocks),
+            },
+            Some(target) if *target != source_version => ExtensionProblem::VersionDiffers {
+                target: target.clone(),
+            },
+            Some(_) => return None,
+        };
+        Some(ExtensionIssue {
+            extension,
+            source_version,
+            problem,
+        })
+    }
+
+    fn is_preloaded(&self, extension: &str) -> bool {
+        !PRELOAD_LIBRARIES.iter().any(|(name, _)| *name == extension)
+            || self.preloaded.iter().any(|l| l == extension)
+    }
+}
+
+/// Compares the extensions installed in source database `db` with what the
+/// target provides.
+///
+/// # Errors
+///
+/// Returns an error if the source database cannot be queried.
+pub async fn check_db(
+    config: &Config,
+    db: &str,
+    target: &TargetExtensions,
+) -> Result<Vec<ExtensionIssue>> {
+    let pool: PgPool = db::pg_pool(
+        &config.from_host,
+        &config.from_port,
+        &config.from_user,
+        &config.from_pass,
+        db,
+    )
+    .await?;
+    let installed: Vec<(String, String)> =
+        sqlx::query_as("SELECT extname::text, extversion FROM pg_extension ORDER BY extname")
+            .fetch_all(&pool)
+            .await?;
+    Ok(installed
+        .into_iter()
+        .filter_map(|(name, version)| target.issue(name, version))
+        .collect())
+}
+
+/// Checks the extensions of every database that still has to be restored and
+/// reports them to the observer.
+///
+/// # Errors
+///
+/// Returns an error if a server cannot be queried, or if any extension cannot
+/// be created on the target.
+pub async fn check_all(
+    config: &Config,
+    dbs: &[String],
+    observer: &dyn MigrationObserver,
+) -> Result<()> {
+    let manifest = manifest::load(config)?;
+    let target = TargetExtensions::load(config).await?;
+    let mut fatal = Vec::new();
+    for db in dbs {
+        if manifest.is_finished(config, db, Phase::Restore) {
+            continue;
+        }
+        let issues = check_db(config, db, &target).await?;
+        fatal.extend(
+            issues
+                .iter()
+                .filter(|i| i.is_fatal())
+                .map(|i| format!("{db}: {} {}", i.extension, i.problem)),
+        );
+        observer.extensions(db, &issues);
+    }
+    if !fatal.is_empty() {
+        anyhow::bail!(
+            "extensions cannot be created on the target:\n  {}",
+            fatal.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Updates the extensions of the restored `db` to the target's default
+/// versions and records the transitions in the manifest.
+///
+/// An extension that fails to update is reported and recorded but does not
+/// fail the step.
+///
+/// # Errors
+///
+/// Returns an error if the target database cannot be queried.
+pub async fn update_db(config: &Config, db: &str, observer: &dyn MigrationObserver) -> Result<()> {
+    observer.phase_started(db, Phase::UpdateExtensions, 0);
+    manifest::phase_started(config, db, Phase::UpdateExtensions)?;
+
+    let result = update_extensions(config, db).await;
+    if let Ok(updates) = &result {
+        for update in updates {
+            if let Some(error) = &update.error {
+                observer.warning(
+                    Some(db),
+                    &format!("could not update extension {}: {error}", update.extension),
+                );
+            }
+        }
+        let updates = updates.clone();
+        manifest::update_database(config, db, |r| r.extension_updates = updates)?;
+    }
+    let result = result.map(|_| ());
+    manifest::phase_finished(config, db, Phase::UpdateExtensions, &result)?;
+    result?;
+
+    observer.phase_finished(db, Phase::UpdateExtensions);
+    Ok(())
+}
+
+async fn update_extensions(config: &Config, db: &str) -> Result<Vec<ExtensionUpdate>> {
+    let pool = db::pg_pool(
+        &config.to_host,
+        &config.to_port,
+        &config.to_user,
+        &config.to_pass,
+        config.target_db(db),
+    )
+    .await?;
+    let outdated: Vec<(String, String)> = sqlx::query_as(
+        "SELECT e.extname::text, e.extversion FROM pg_extension e \
+         JOIN pg_available_extensions a ON a.name = e.extname \
+         WHERE e.extversion IS DISTINCT FROM a.default_version ORDER BY e.extname",
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut updates = Vec::with_capacity(outdated.len());
+    for (extension, from) in outdated {
+        let statement = format!(
+            "ALTER EXTENSION \"{}\" UPDATE",
+            extension.replace('"', "\"\"")
+        );
+        let update = match sqlx::query(&statement).execute(&pool).await {
+            Ok(_) => ExtensionUpdate {
+                to: Some(
+                    sqlx::query_scalar("SELECT extversion FROM pg_extension WHERE extname = $1")
+                        .bind(&extension)
+                        .fetch_one(&pool)
+                        .await?,
+                ),
+                extension,
+                from,
+                error: None,
+            },
+            Err(e) => ExtensionUpdate {
+                extension,
+                from,
+                to: None,
+                error: Some(e.to_string()),
+            },
+        };
+        updates.push(update);
+    }
+    Ok(updates)
+}
@@ -0,0 +1,148 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Include/exclude rules applied to names discovered on the source.
+///
+/// Patterns are shell-style globs (`app_*`, `db?`, `tenant_[0-9]*`) unless
+/// prefixed with `re:`, in which case the rest is a regular expression
+/// (`re:^tenant_\d+$`). Globs always match the whole name, regexes match
+/// anywhere unless anchored.
+#[derive(Clone, Debug, Default)]
+pub struct NameFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl NameFilter {
+    /// Compiles the given include and exclude patterns.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any pattern is not a valid glob or regex.
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Self {
+            include: include.iter().map(|p| compile(p)).collect::<Result<_>>()?,
+            exclude: exclude.iter().map(|p| compile(p)).collect::<Result<_>>()?,
+        })
+    }
+
+    /// Returns true if `name` passes the filter: it matches at least one
+    /// include pattern (or there are none) and no exclude pattern.
+    #[must_use]
+    pub fn matches(&self, name: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|r| r.is_match(name));
+        included && !self.exclude.iter().any(|r| r.is_match(name))
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    let source = pattern
+        .strip_prefix("re:")
+        .map_or_else(|| glob_to_regex(pattern), str::to_string);
+    Regex::new(&source).with_context(|| format!("invalid pattern '{pattern}'"))
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut out = String::with_capacity(glob.len() + 2);
+    out.push('^');
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => out.push_str(".*"),
+            '?' => out.push('.'),
+            '[' => {
+                out.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    out.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+                out.push(']');
+            }
+            _ => out.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    out.push('$');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> NameFilter {
+        let owned = |p: &[&str]| p.iter().map(ToString::to_string).collect::<Vec<_>>();
+        NameFilter::new(&owned(include), &owned(exclude)).expect("valid patterns")
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(NameFilter::default().matches("anything"));
+    }
+
+    #[test]
+    fn globs_match_the_whole_name() {
+        let f = filter(&["app_*", "db?"], &[]);
+        assert!(f.matches("app_"));
+        assert!(f.matches("app_v2"));
+        assert!(f.matches("db1"));
+        assert!(!f.matches("db12"));
+        assert!(!f.matches("my_app_v2"));
+    }
+
+    #[test]
+    fn globs_escape_regex_characters() {
+        let f = filter(&["a.b", "c+d", "(e)|f", "g$h"], &[]);
+        assert!(f.matches("a.b"));
+        assert!(!f.matches("aXb"));
+        assert!(f.matches("c+d"));
+        assert!(!f.matches("ccd"));
+        assert!(f.matches("(e)|f"));
+        assert!(!f.matches("e"));
+        assert!(f.matches("g$h"));
+    }
+
+    #[test]
+    fn globs_support_brackets() {
+        let f = filter(&["tenant_[0-9]*", "x[!a]y", "z[[\\]"], &[]);
+        assert!(f.matches("tenant_7"));
+        assert!(f.matches("tenant_42_eu"));
+        assert!(!f.matches("tenant_x"));
+        assert!(f.matches("xby"));
+        assert!(!f.matches("xay"));
+        assert!(f.matches("z["));
+        assert!(f.matches("z\\"));
+    }
+
+    #[test]
+    fn regexes_match_anywhere_unless_anchored() {
+        let f = filter(&["re:tenant_\\d+"], &[]);
+        assert!(f.matches("old_tenant_12_copy"));
+        let f = filter(&["re:^tenant_\\d+$"], &[]);
+        assert!(f.matches("tenant_12"));
+        assert!(!f.matches("tenant_12_copy"));
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let f = filter(&["app*"], &["*_tmp", "re:^app_old"]);
+        assert!(f.matches("app_v2"));
+        assert!(!f.matches("app_tmp"));
+        assert!(!f.matches("app_old_1"));
+        assert!(!f.matches("billing"));
+    }
+
+    #[test]
+    fn rejects_invalid_regexes() {
+        assert!(NameFilter::new(&["re:(".to_string()], &[]).is_err());
+        assert!(NameFilter::new(&[], &["re:[a".to_string()]).is_err());
+    }
+}
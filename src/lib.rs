@@ -0,0 +1,38 @@
+//! Migrates databases and global objects between two `PostgreSQL` servers.
+//!
+//! The `pg-migrate` binary is a thin CLI over this crate. Embedders usually
+//! start from [`Migration::builder`]; the [`db`], [`phases`] and
+//! [`verification`] modules expose the individual steps.
+
+pub mod collations;
+pub mod commands;
+pub mod compat;
+pub mod config;
+pub mod db;
+pub mod dumps;
+pub mod extensions;
+pub mod filter;
+pub mod manifest;
+pub mod migration;
+pub mod observer;
+pub mod phases;
+pub mod preflight;
+pub mod roles;
+pub mod schedule;
+pub mod script;
+pub mod settings;
+pub mod tui;
+pub mod verification;
+
+pub use crate::config::Config;
+pub use crate::migration::{Endpoint, Migration, MigrationBuilder, MigrationHandle};
+pub use crate::observer::{MigrationObserver, NoopObserver, Phase};
+use std::{env, path::PathBuf};
+
+/// Returns the user's home directory, if `HOME` is set and not empty.
+#[must_use]
+pub fn home() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|h| !h.is_empty())
+        .map(PathBuf::from)
+}
@@ -1,121 +1,323 @@
-mod db;
-mod phases;
-mod tui;
-mod verification;
-
-use crate::phases::{
-    phase_compute_source_counts, phase_dump_all, phase_restore_all, phase_verify_all,
-};
-use crate::tui::migration_style;
 use anyhow::Result;
-use clap::Parser;
+use clap::{Args as ClapArgs, Parser, Subcommand};
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
-use log::info;
+use log::{error, info};
+use pg_migrate::{
+    Config, MigrationObserver,
+    commands::{self, DryRunScope},
+    config::{FileConfig, RestartPolicy, ScheduleOrder, StaleDumpPolicy, parse_duration},
+    manifest,
+    tui::{IndicatifObserver, render_summary},
+};
 use std::{
-    collections::HashMap,
-    env, fs,
     path::PathBuf,
     sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::sync::Semaphore;
 use tokio_util::sync::CancellationToken;
 
-pub struct Config {
-    pub from_host: String,
-    pub from_port: String,
-    pub from_user: String,
-    pub from_pass: String,
-    pub from_db: String,
-
-    pub to_host: String,
-    pub to_port: String,
-    pub to_user: String,
-    pub to_pass: String,
-    pub to_db: String,
-
-    pub dump_jobs: usize,
-    pub restore_jobs: usize,
-    pub max_parallel: usize,
-
-    pub dump_root: PathBuf,
-    pub migrate_globals: bool,
-    pub disable_dst_optimizations: bool,
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    args: Args,
 }
 
-/// Returns the user's home directory.
-///
-/// # Panics
-///
-/// Panics if the `HOME` environment variable is not set.
-#[must_use]
-pub fn home() -> PathBuf {
-    PathBuf::from(env::var("HOME").expect("HOME not set"))
+/// Without a subcommand every phase runs in one pass, same as `run`.
+#[derive(Subcommand, Clone, Copy)]
+enum Command {
+    /// Run every phase: globals, dump, restore and verify
+    Run,
+    /// Show the full plan without changing anything (same as `run --dry-run`)
+    Plan,
+    /// Check connectivity, privileges, client tools and disk space
+    Preflight,
+    /// Dump the source databases and record their row counts
+    Dump,
+    /// Restore existing dumps into the target
+    Restore,
+    /// Compare source and target row counts
+    Verify,
+    /// Migrate roles and other global objects only
+    Globals,
+    /// Put back target settings left in fast restore mode by an interrupted run
+    Recover,
 }
 
-/// Returns the directory used for state markers.
-///
-/// # Panics
-///
-/// Panics if the `HOME` environment variable is not set.
-#[must_use]
-pub fn state_dir() -> PathBuf {
-    home().join("pg_migrate_state")
+impl Command {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Run => "Migration",
+            Self::Plan => "Plan",
+            Self::Preflight => "Preflight",
+            Self::Dump => "Dump",
+            Self::Restore => "Restore",
+            Self::Verify => "Verification",
+            Self::Globals => "Globals migration",
+            Self::Recover => "Recovery",
+        }
+    }
 }
 
-/// Returns the directory used for verification markers.
-///
-/// # Panics
-///
-/// Panics if the `HOME` environment variable is not set.
-#[must_use]
-pub fn verify_dir() -> PathBuf {
-    home().join("pg_verify_state")
+#[derive(ClapArgs)]
+struct Args {
+    /// TOML file with source, target and per-database settings.
+    #[arg(long, global = true, env = "PGMIGRATE_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[arg(long, global = true, env = "PGMIGRATE_FROM_HOST")]
+    from_host: Option<String>,
+    #[arg(long, global = true, env = "PGMIGRATE_FROM_PORT")]
+    from_port: Option<String>,
+    #[arg(long, global = true, env = "PGMIGRATE_FROM_USER")]
+    from_user: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        env = "PGMIGRATE_FROM_PASS",
+        hide_env_values = true
+    )]
+    from_pass: Option<String>,
+    #[arg(long, global = true, env = "PGMIGRATE_FROM_DB")]
+    from_db: Option<String>,
+
+    #[arg(long, global = true, env = "PGMIGRATE_TO_HOST")]
+    to_host: Option<String>,
+    #[arg(long, global = true, env = "PGMIGRATE_TO_PORT")]
+    to_port: Option<String>,
+    #[arg(long, global = true, env = "PGMIGRATE_TO_USER")]
+    to_user: Option<String>,
+    #[arg(long, global = true, env = "PGMIGRATE_TO_PASS", hide_env_values = true)]
+    to_pass: Option<String>,
+    #[arg(long, global = true, env = "PGMIGRATE_TO_DB")]
+    to_db: Option<String>,
+
+    #[arg(long, global = true, env = "PGMIGRATE_DUMP_JOBS")]
+    dump_jobs: Option<usize>,
+    #[arg(long, global = true, env = "PGMIGRATE_RESTORE_JOBS")]
+    restore_jobs: Option<usize>,
+    #[arg(short = 'p', long, global = true, env = "PGMIGRATE_MAX_PARALLEL")]
+    max_parallel: Option<usize>,
+    #[arg(long, global = true, env = "PGMIGRATE_COMPRESSION")]
+    compression: Option<String>,
+    /// Cap on `pg_dump`/`pg_restore` jobs across all running databases, shared by size.
+    #[arg(long, global = true, env = "PGMIGRATE_JOBS_BUDGET")]
+    jobs_budget: Option<usize>,
+    /// Start databases largest-first or smallest-first.
+    #[arg(long, global = true, env = "PGMIGRATE_ORDER")]
+    order: Option<ScheduleOrder>,
+    /// Start databases matching this glob (or `re:<regex>`) first; repeatable, in order.
+    #[arg(
+        long,
+        global = true,
+        env = "PGMIGRATE_PRIORITY_DB",
+        value_delimiter = ','
+    )]
+    priority_db: Vec<String>,
+    #[arg(long, global = true, env = "PGMIGRATE_DUMP_ROOT")]
+    dump_root: Option<PathBuf>,
+    /// Directory holding the state of every run (defaults to `$HOME/pg_migrate_state`).
+    #[arg(long, global = true, env = "PGMIGRATE_STATE_DIR")]
+    state_dir: Option<PathBuf>,
+    /// Names this run's state directory (defaults to the source and target host:port).
+    #[arg(long, global = true, env = "PGMIGRATE_RUN_ID")]
+    run_id: Option<String>,
+    /// What to do with an existing dump that is incomplete, unreadable, from another source or too old: redump, fail or reuse.
+    #[arg(long, global = true, env = "PGMIGRATE_STALE_DUMP_POLICY")]
+    stale_dump_policy: Option<StaleDumpPolicy>,
+    /// Treat existing dumps older than this as stale, e.g. `12h` or `7d`.
+    #[arg(long, global = true, env = "PGMIGRATE_MAX_DUMP_AGE", value_parser = parse_duration)]
+    max_dump_age: Option<Duration>,
+    #[arg(long, global = true, env = "PGMIGRATE_MIGRATE_GLOBALS", num_args = 0..=1, default_missing_value = "true")]
+    migrate_globals: Option<bool>,
+    #[arg(long, global = true, env = "PGMIGRATE_DISABLE_DST_OPTIMIZATIONS", num_args = 0..=1, default_missing_value = "true")]
+    disable_dst_optimizations: Option<bool>,
+    /// Target settings used for the restore: conservative, default, aggressive or a profile from the config file.
+    #[arg(long, global = true, env = "PGMIGRATE_FAST_RESTORE_PROFILE")]
+    fast_restore_profile: Option<String>,
+    /// What to do with profile settings that need a target restart: skip or prompt.
+    #[arg(long, global = true, env = "PGMIGRATE_RESTART_POLICY")]
+    restart_policy: Option<RestartPolicy>,
+    /// Pipe `pg_dump` straight into `pg_restore` instead of writing dumps to disk.
+    #[arg(long, global = true, env = "PGMIGRATE_STREAM", num_args = 0..=1, default_missing_value = "true")]
+    stream: Option<bool>,
+    /// Run `ALTER EXTENSION ... UPDATE` in every database after its restore.
+    #[arg(long, global = true, env = "PGMIGRATE_UPDATE_EXTENSIONS", num_args = 0..=1, default_missing_value = "true")]
+    update_extensions: Option<bool>,
+    /// Run `vacuumdb --analyze-in-stages` in every database after its restore.
+    #[arg(long, global = true, env = "PGMIGRATE_ANALYZE", num_args = 0..=1, default_missing_value = "true")]
+    analyze: Option<bool>,
+    /// Rebuild indexes on collations whose version changed after the restore.
+    #[arg(long, global = true, env = "PGMIGRATE_REINDEX_COLLATIONS", num_args = 0..=1, default_missing_value = "true")]
+    reindex_collations: Option<bool>,
+    /// Go ahead even if the compatibility scan finds objects the target cannot restore.
+    #[arg(long, global = true, env = "PGMIGRATE_IGNORE_INCOMPATIBLE", num_args = 0..=1, default_missing_value = "true")]
+    ignore_incompatible: Option<bool>,
+
+    /// Only migrate databases matching this glob (or `re:<regex>`); repeatable.
+    #[arg(
+        long,
+        global = true,
+        env = "PGMIGRATE_INCLUDE_DB",
+        value_delimiter = ','
+    )]
+    include_db: Vec<String>,
+    /// Never migrate databases matching this glob (or `re:<regex>`); repeatable.
+    #[arg(
+        long,
+        global = true,
+        env = "PGMIGRATE_EXCLUDE_DB",
+        value_delimiter = ','
+    )]
+    exclude_db: Vec<String>,
+    /// Also migrate the contents of the `postgres` database.
+    #[arg(long, global = true, env = "PGMIGRATE_INCLUDE_POSTGRES_DB", num_args = 0..=1, default_missing_value = "true")]
+    include_postgres_db: Option<bool>,
+    /// Restore a source database under another name, as `source=target`; repeatable.
+    #[arg(long, global = true, env = "PGMIGRATE_RENAME_DB", value_delimiter = ',', value_parser = parse_rename)]
+    rename_db: Vec<(String, String)>,
+
+    /// Leave roles matching this glob (or `re:<regex>`) out of the globals; repeatable.
+    #[arg(
+        long,
+        global = true,
+        env = "PGMIGRATE_EXCLUDE_ROLE",
+        value_delimiter = ','
+    )]
+    exclude_role: Vec<String>,
+    /// Give a source role another name on the target, as `source=target`; repeatable.
+    #[arg(long, global = true, env = "PGMIGRATE_RENAME_ROLE", value_delimiter = ',', value_parser = parse_rename)]
+    rename_role: Vec<(String, String)>,
+
+    /// Print the plan, settings, globals and commands without changing anything.
+    #[arg(long, global = true, env = "PGMIGRATE_DRY_RUN")]
+    dry_run: bool,
 }
 
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    #[arg(long, default_value = "localhost")]
-    from_host: String,
-    #[arg(long, default_value = "5432")]
-    from_port: String,
-    #[arg(long, default_value = "postgres")]
-    from_user: String,
-    #[arg(long, default_value = "oldpass")]
-    from_pass: String,
-    #[arg(long, default_value = "postgres")]
-    from_db: String,
-
-    #[arg(long, default_value = "localhost")]
-    to_host: String,
-    #[arg(long, default_value = "5432")]
-    to_port: String,
-    #[arg(long, default_value = "postgres")]
-    to_user: String,
-    #[arg(long, default_value = "newpass")]
-    to_pass: String,
-    #[arg(long, default_value = "postgres")]
-    to_db: String,
-
-    #[arg(long, default_value_t = 24)]
-    dump_jobs: usize,
-    #[arg(long, default_value_t = 12)]
-    restore_jobs: usize,
-    #[arg(short = 'p', long, default_value_t = 6)]
-    max_parallel: usize,
-    #[arg(long, default_value = "pg_dumps")]
-    dump_root: String,
-    #[arg(long, default_value_t = true)]
-    migrate_globals: bool,
-    #[arg(long, default_value_t = false)]
-    disable_dst_optimizations: bool,
+fn parse_rename(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err(format!("expected SOURCE=TARGET, got '{s}'")),
+    }
+}
+
+impl Args {
+    /// Builds the effective configuration.
+    ///
+    /// Precedence, lowest to highest: built-in defaults, the `--config` file,
+    /// `PGMIGRATE_*` environment variables and finally CLI flags (clap resolves
+    /// the last two).
+    fn into_config(self) -> Result<Config> {
+        let mut config = Config::default();
+        if let Some(path) = &self.config {
+            config.apply_file(FileConfig::load(path)?);
+        }
+
+        let overrides = [
+            (self.from_host, &mut config.from_host),
+            (self.from_port, &mut config.from_port),
+            (self.from_user, &mut config.from_user),
+            (self.from_pass, &mut config.from_pass),
+            (self.from_db, &mut config.from_db),
+            (self.to_host, &mut config.to_host),
+            (self.to_port, &mut config.to_port),
+            (self.to_user, &mut config.to_user),
+            (self.to_pass, &mut config.to_pass),
+            (self.to_db, &mut config.to_db),
+            (self.compression, &mut config.compression),
+        ];
+        for (value, field) in overrides {
+            if let Some(v) = value {
+                *field = v;
+            }
+        }
+
+        let switches = [
+            (self.migrate_globals, &mut config.migrate_globals),
+            (
+                self.disable_dst_optimizations,
+                &mut config.disable_dst_optimizations,
+            ),
+            (self.stream, &mut config.stream),
+            (self.update_extensions, &mut config.update_extensions),
+            (self.analyze, &mut config.analyze),
+            (self.reindex_collations, &mut config.reindex_collations),
+            (self.ignore_incompatible, &mut config.ignore_incompatible),
+            (self.include_postgres_db, &mut config.include_postgres_db),
+        ];
+        for (value, field) in switches {
+            if let Some(v) = value {
+                *field = v;
+            }
+        }
+
+        if let Some(v) = self.dump_jobs {
+            config.dump_jobs = v;
+        }
+        if let Some(v) = self.restore_jobs {
+            config.restore_jobs = v;
+        }
+        if let Some(v) = self.max_parallel {
+            config.max_parallel = v;
+        }
+        if let Some(v) = self.jobs_budget {
+            config.jobs_budget = Some(v);
+        }
+        if let Some(v) = self.order {
+            config.order = v;
+        }
+        if !self.priority_db.is_empty() {
+            config.priority_dbs = self.priority_db;
+        }
+        if let Some(v) = self.dump_root {
+            config.dump_root = v;
+        }
+        if let Some(v) = self.state_dir {
+            config.state_root = Some(v);
+        }
+        if let Some(v) = self.run_id {
+            config.run_id = Some(v);
+        }
+        if let Some(v) = self.stale_dump_policy {
+            config.stale_dump_policy = v;
+        }
+        if let Some(v) = self.max_dump_age {
+            config.max_dump_age = Some(v);
+        }
+        if let Some(v) = self.fast_restore_profile {
+            config.fast_restore_profile = v;
+        }
+        if let Some(v) = self.restart_policy {
+            config.restart_policy = v;
+        }
+        if !self.include_db.is_empty() {
+            config.include_dbs = self.include_db;
+        }
+        if !self.exclude_db.is_empty() {
+            config.exclude_dbs = self.exclude_db;
+        }
+        for (from, to) in self.rename_db {
+            config.databases.entry(from).or_default().target = Some(to);
+        }
+        if !self.exclude_role.is_empty() {
+            config.exclude_roles = self.exclude_role;
+        }
+        config.role_renames.extend(self.rename_role);
+        config.dry_run = self.dry_run;
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let start_time = Instant::now();
-    let args = Args::parse();
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Run);
 
     let logger =
         env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).build();
@@ -128,6 +330,29 @@ async fn main() -> Result<()> {
         .try_init()
         .expect("failed to init log wrapper");
 
+    let config = Arc::new(cli.args.into_config()?);
+
+    if matches!(command, Command::Preflight) {
+        return commands::preflight(&config).await;
+    }
+
+    if matches!(command, Command::Recover) {
+        return commands::recover(&config).await;
+    }
+
+    if matches!(command, Command::Plan) || config.dry_run {
+        let scope = match command {
+            Command::Run | Command::Plan | Command::Preflight | Command::Recover => {
+                DryRunScope::ALL
+            }
+            Command::Dump => DryRunScope::DUMP,
+            Command::Restore => DryRunScope::RESTORE,
+            Command::Verify => DryRunScope::NONE,
+            Command::Globals => DryRunScope::GLOBALS,
+        };
+        return commands::dry_run(&config, scope).await;
+    }
+
     let total_time_pb = mp.add(ProgressBar::new_spinner());
     total_time_pb.set_style(
         ProgressStyle::with_template("{spinner:.green} Total elapsed time: {elapsed_precise}")
@@ -135,28 +360,6 @@ async fn main() -> Result<()> {
     );
     total_time_pb.enable_steady_tick(Duration::from_millis(100));
 
-    let config = Arc::new(Config {
-        from_host: args.from_host,
-        from_port: args.from_port,
-        from_user: args.from_user,
-        from_pass: args.from_pass,
-        from_db: args.from_db,
-        to_host: args.to_host,
-        to_port: args.to_port,
-        to_user: args.to_user,
-        to_pass: args.to_pass,
-        to_db: args.to_db,
-        dump_jobs: args.dump_jobs,
-        restore_jobs: args.restore_jobs,
-        max_parallel: args.max_parallel,
-        dump_root: args.dump_root.into(),
-        migrate_globals: args.migrate_globals,
-        disable_dst_optimizations: args.disable_dst_optimizations,
-    });
-
-    fs::create_dir_all(state_dir())?;
-    fs::create_dir_all(verify_dir())?;
-
     let cancel = CancellationToken::new();
     let cancel_signal = cancel.clone();
 
@@ -168,58 +371,34 @@ async fn main() -> Result<()> {
         cancel_signal.cancel();
     });
 
-    let dbs_with_sizes = db::discover_databases(&config).await?;
-    let db_names_owned: Vec<String> = dbs_with_sizes.iter().map(|(n, _)| n.clone()).collect();
-
-    info!("Databases: {db_names_owned:?}");
-
-    if dbs_with_sizes.is_empty() {
-        info!("No databases found to migrate.");
-        return Ok(());
-    }
-
-    let mut pbs = HashMap::new();
-    for (db, _size) in &dbs_with_sizes {
-        let pb = mp.add(ProgressBar::new(0));
-        pb.set_style(migration_style()?);
-        pb.enable_steady_tick(Duration::from_secs(1));
-        pbs.insert(db.clone(), pb);
-    }
-
-    if !config.disable_dst_optimizations {
-        db::enable_fast_restore(&config).await?;
-    }
-
-    if config.migrate_globals {
-        db::migrate_globals(&config).await?;
-    }
-
-    db::create_dbs(&config, &db_names_owned).await?;
-
-    let sem = Arc::new(Semaphore::new(config.max_parallel));
+    let observer: Arc<dyn MigrationObserver> = Arc::new(IndicatifObserver::new((*mp).clone()));
 
-    // Phase 1: Dump all databases in parallel
-    phase_dump_all(&config, &dbs_with_sizes, &pbs, &cancel, sem.clone()).await?;
+    let result = match command {
+        Command::Run => commands::run(&config, &observer, &cancel).await,
+        Command::Plan | Command::Preflight | Command::Recover => unreachable!("handled above"),
+        Command::Dump => commands::dump(&config, &observer, &cancel).await,
+        Command::Restore => commands::restore(&config, &observer, &cancel).await,
+        Command::Verify => commands::verify(&config, &*observer).await,
+        Command::Globals => commands::globals(&config, &*observer).await,
+    };
 
-    // Phase 2: Compute source row counts sequentially
-    phase_compute_source_counts(&config, &db_names_owned).await?;
-
-    // Phase 3: Restore all databases in parallel
-    phase_restore_all(&config, &dbs_with_sizes, &pbs, &cancel, sem).await?;
-
-    // Phase 4: Compute destination row counts and verify
-    phase_verify_all(&config, &db_names_owned, &pbs).await?;
+    total_time_pb.finish_and_clear();
 
-    if !config.disable_dst_optimizations {
-        db::restore_safe_settings(&config).await?;
+    // The summary matters most when the command failed, so print it then too
+    if !matches!(command, Command::Globals) {
+        match manifest::load(&config) {
+            Ok(manifest) => print!("{}", render_summary(&manifest)),
+            Err(e) if result.is_ok() => return Err(e),
+            Err(e) => error!("Cannot print the summary: {e:#}"),
+        }
     }
-
-    total_time_pb.finish_and_clear();
+    result?;
 
     let elapsed = start_time.elapsed();
 
     info!(
-        "Migration complete in {}.",
+        "{} complete in {}.",
+        command.name(),
         indicatif::HumanDuration(elapsed)
     );
 
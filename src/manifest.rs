@@ -0,0 +1,477 @@
+use crate::Config;
+use crate::observer::Phase;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Version of the manifest layout written by this build.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Serialises read-modify-write cycles between the tasks of one process.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Progress of one run, stored as `manifest.json` in its state directory.
+///
+/// This is the single source of truth for resuming, reporting and external
+/// tooling; timestamps are seconds since the Unix epoch.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Manifest {
+    pub version: u32,
+    pub run_id: String,
+    /// Source server as `host:port`.
+    pub source: String,
+    /// Target server as `host:port`.
+    pub target: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub source_server_version: Option<String>,
+    /// `--version` output of the client tools, keyed by program name.
+    pub tool_versions: BTreeMap<String, String>,
+    pub globals: Option<PhaseRecord>,
+    /// Outcome of every statement of the globals script, in order.
+    pub globals_statements: Vec<GlobalsStatement>,
+    /// Target settings as they were before the fast restore changed them;
+    /// cleared once they are put back.
+    pub target_settings: Option<SettingsSnapshot>,
+    /// How the target was tuned for the last restore.
+    pub tuning: Option<Tuning>,
+    /// Source names of renamed roles created on the target for the restores,
+    /// mapped to the new names; cleared once they are dropped.
+    pub placeholder_roles: BTreeMap<String, String>,
+    /// Per-database progress, keyed by source database name.
+    pub databases: BTreeMap<String, DatabaseRecord>,
+}
+
+/// Progress of one source database.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatabaseRecord {
+    /// Name of the database on the target.
+    pub target: String,
+    /// Source size in bytes at discovery.
+    pub size: u64,
+    /// Size in bytes of the finished dump.
+    pub dump_bytes: Option<u64>,
+    pub dump: Option<PhaseRecord>,
+    /// Source row counts keyed by `schema.table`, taken once per dump.
+    pub source_counts: Option<BTreeMap<String, String>>,
+    pub restore: Option<PhaseRecord>,
+    pub update_extensions: Option<PhaseRecord>,
+    /// Extensions that `update_extensions` moved to another version or failed on.
+    pub extension_updates: Vec<ExtensionUpdate>,
+    pub collations: Option<PhaseRecord>,
+    /// Collations whose version differs from the source or from the one
+    /// recorded on the target.
+    pub collation_changes: Vec<CollationChange>,
+    pub analyze: Option<PhaseRecord>,
+    /// Set once what the placeholder roles own here was handed to the
+    /// renamed roles.
+    pub roles_reassigned: bool,
+    pub verify: Option<PhaseRecord>,
+}
+
+impl DatabaseRecord {
+    #[must_use]
+    pub const fn phase(&self, phase: Phase) -> Option<&PhaseRecord> {
+        match phase {
+            Phase::Dump => self.dump.as_ref(),
+            Phase::Restore => self.restore.as_ref(),
+            Phase::UpdateExtensions => self.update_extensions.as_ref(),
+            Phase::Collations => self.collations.as_ref(),
+            Phase::Analyze => self.analyze.as_ref(),
+            Phase::Verify => self.verify.as_ref(),
+        }
+    }
+
+    const fn phase_mut(&mut self, phase: Phase) -> &mut Option<PhaseRecord> {
+        match phase {
+            Phase::Dump => &mut self.dump,
+            Phase::Restore => &mut self.restore,
+            Phase::UpdateExtensions => &mut self.update_extensions,
+            Phase::Collations => &mut self.collations,
+            Phase::Analyze => &mut self.analyze,
+            Phase::Verify => &mut self.verify,
+        }
+    }
+
+    /// Returns the last phase that finished successfully.
+    #[must_use]
+    pub fn state(&self) -> &'static str {
+        [Phase::Verify, Phase::Restore, Phase::Dump]
+            .into_iter()
+            .find(|p| self.phase(*p).is_some_and(PhaseRecord::is_finished))
+            .map_or("pending", |p| match p {
+                Phase::Dump => "dumped",
+                Phase::Restore | Phase::UpdateExtensions | Phase::Collations | Phase::Analyze => {
+                    "restored"
+                }
+                Phase::Verify => "verified",
+            })
+    }
+}
+
+/// Outcome of `ALTER EXTENSION ... UPDATE` for one extension.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExtensionUpdate {
+    pub extension: String,
+    /// Version right after the restore.
+    pub from: String,
+    /// Version after the update; unset if it failed.
+    pub to: Option<String>,
+    pub error: Option<String>,
+}
+
+/// A collation whose version changed on the way to the target.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CollationChange {
+    /// Qualified collation name, or `default` for the database's own.
+    pub collation: String,
+    /// Version recorded on the source, if it tracks one.
+    pub source_version: Option<String>,
+    /// Version recorded on the target.
+    pub recorded: Option<String>,
+    /// Version the target's collation library provides.
+    pub actual: Option<String>,
+    /// User indexes sorted by the collation.
+    pub indexes: Vec<String>,
+    /// Set once the indexes were rebuilt and the recorded version refreshed.
+    pub reindexed: bool,
+    pub error: Option<String>,
+}
+
+impl CollationChange {
+    /// Returns true if the target's recorded version no longer matches its
+    /// library, so indexes built under the old version may be corrupt.
+    #[must_use]
+    pub fn is_stale(&self) -> bool {
+        self.recorded.is_some() && self.recorded != self.actual
+    }
+}
+
+/// Outcome of one statement of the globals script.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GlobalsStatement {
+    /// Statement text with passwords left out.
+    pub statement: String,
+    pub outcome: StatementOutcome,
+    /// Why the statement was skipped, or the error it failed with.
+    pub detail: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementOutcome {
+    #[default]
+    Applied,
+    Skipped,
+    Failed,
+}
+
+/// Target server settings captured before the migration changed them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SettingsSnapshot {
+    pub taken_at: u64,
+    /// Original state of every changed setting, keyed by name.
+    pub settings: BTreeMap<String, OriginalSetting>,
+}
+
+/// State of one target setting before the migration changed it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OriginalSetting {
+    /// Value in effect, as shown by `pg_settings`.
+    pub value: String,
+    pub unit: Option<String>,
+    /// Where the value came from, e.g. `default` or `configuration file`.
+    pub source: String,
+    /// When a change takes effect; `postmaster` settings need a restart.
+    pub context: String,
+    /// Value set with `ALTER SYSTEM` in `postgresql.auto.conf`, if any.
+    pub auto_conf: Option<String>,
+}
+
+/// How the target was tuned for the restore.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TuningMode {
+    /// Left as it was.
+    #[default]
+    None,
+    /// Server-wide with `ALTER SYSTEM`.
+    System,
+    /// Per `pg_restore` session through `PGOPTIONS`, as the target refused
+    /// `ALTER SYSTEM`.
+    Session,
+}
+
+/// Target tuning in effect for the restore.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tuning {
+    pub mode: TuningMode,
+    /// Name of the fast restore profile.
+    pub profile: String,
+    /// Settings that were applied, keyed by name.
+    pub applied: BTreeMap<String, String>,
+    /// Profile settings that were left out, with the reason.
+    pub skipped: BTreeMap<String, String>,
+}
+
+/// Timing and outcome of one step.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PhaseRecord {
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    /// Set if the step failed; cleared when it is retried.
+    pub error: Option<String>,
+}
+
+impl PhaseRecord {
+    /// Returns true if the step ran to completion without an error.
+    #[must_use]
+    pub const fn is_finished(&self) -> bool {
+        self.finished_at.is_some() && self.error.is_none()
+    }
+
+    /// Returns how long the step took, if it has ended.
+    #[must_use]
+    pub fn duration(&self) -> Option<Duration> {
+        self.finished_at
+            .map(|f| Duration::from_secs(f.saturating_sub(self.started_at)))
+    }
+
+    fn started() -> Self {
+        Self {
+            started_at: now(),
+            ..Self::default()
+        }
+    }
+
+    fn finish(&mut self, result: &Result<()>) {
+        self.finished_at = Some(now());
+        self.error = result.as_ref().err().map(|e| format!("{e:#}"));
+    }
+}
+
+impl Manifest {
+    fn new(config: &Config) -> Self {
+        let now = now();
+        Self {
+            version: MANIFEST_VERSION,
+            run_id: config.run_id(),
+            source: format!("{}:{}", config.from_host, config.from_port),
+            target: format!("{}:{}", config.to_host, config.to_port),
+            created_at: now,
+            updated_at: now,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the record of `db`, unless it was migrated under another target name.
+    #[must_use]
+    pub fn database(&self, config: &Config, db: &str) -> Option<&DatabaseRecord> {
+        self.databases
+            .get(db)
+            .filter(|r| r.target == config.target_db(db))
+    }
+
+    /// Returns true if `phase` has completed for `db` into its current target.
+    #[must_use]
+    pub fn is_finished(&self, config: &Config, db: &str, phase: Phase) -> bool {
+        self.database(config, db)
+            .and_then(|r| r.phase(phase))
+            .is_some_and(PhaseRecord::is_finished)
+    }
+
+    /// Returns true if a restore of `db` into its current target was started
+    /// before, so the target database may already hold objects.
+    #[must_use]
+    pub fn restore_attempted(&self, config: &Config, db: &str) -> bool {
+        self.database(config, db)
+            .is_some_and(|r| r.restore.is_some())
+    }
+
+    /// Returns true if global objects have been migrated.
+    #[must_use]
+    pub fn globals_finished(&self) -> bool {
+        self.globals.as_ref().is_some_and(PhaseRecord::is_finished)
+    }
+
+    /// Returns the record of `db`, starting a fresh one if the database is new
+    /// or is now restored under a different name.
+    fn database_mut(&mut self, config: &Config, db: &str) -> &mut DatabaseRecord {
+        let target = config.target_db(db);
+        let record = self.databases.entry(db.to_string()).or_default();
+        if record.target != target {
+            *record = DatabaseRecord {
+                target: target.to_string(),
+                ..DatabaseRecord::default()
+            };
+        }
+        record
+    }
+}
+
+/// Returns where the manifest of this run is stored.
+#[must_use]
+pub fn manifest_path(config: &Config) -> PathBuf {
+    config.state_dir().join("manifest.json")
+}
+
+/// Reads the manifest of this run, or returns an empty one if there is none yet.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be read or parsed, or was written
+/// by a newer version of the tool.
+pub fn load(config: &Config) -> Result<Manifest> {
+    let path = manifest_path(config);
+    if !path.exists() {
+        return Ok(Manifest::new(config));
+    }
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("failed to read manifest {}", path.display()))?;
+    let manifest: Manifest = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse manifest {}", path.display()))?;
+    if manifest.version > MANIFEST_VERSION {
+        anyhow::bail!(
+            "manifest {} has version {}, this build understands up to {MANIFEST_VERSION}",
+            path.display(),
+            manifest.version
+        );
+    }
+    Ok(manifest)
+}
+
+/// Applies `f` to the manifest and writes it back atomically.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be read or written.
+///
+/// # Panics
+///
+/// Panics if another thread panicked while updating the manifest.
+pub fn update(config: &Config, f: impl FnOnce(&mut Manifest)) -> Result<()> {
+    let _guard = LOCK.lock().expect("manifest lock poisoned");
+    let mut manifest = load(config)?;
+    f(&mut manifest);
+    manifest.version = MANIFEST_VERSION;
+    manifest.updated_at = now();
+
+    let path = manifest_path(config);
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(&manifest)?)
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, &path).with_context(|| format!("failed to replace {}", path.display()))
+}
+
+/// Records the databases selected for this run and their source sizes.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be updated.
+pub fn record_databases(config: &Config, dbs_with_sizes: &[(String, u64)]) -> Result<()> {
+    update(config, |m| {
+        for (db, size) in dbs_with_sizes {
+            m.database_mut(config, db).size = *size;
+        }
+    })
+}
+
+/// Records that `phase` started for `db`, clearing any earlier outcome.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be updated.
+pub fn phase_started(config: &Config, db: &str, phase: Phase) -> Result<()> {
+    update(config, |m| {
+        *m.database_mut(config, db).phase_mut(phase) = Some(PhaseRecord::started());
+    })
+}
+
+/// Records the outcome of `phase` for `db`.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be updated.
+pub fn phase_finished(config: &Config, db: &str, phase: Phase, result: &Result<()>) -> Result<()> {
+    update(config, |m| {
+        m.database_mut(config, db)
+            .phase_mut(phase)
+            .get_or_insert_with(PhaseRecord::started)
+            .finish(result);
+    })
+}
+
+/// Marks the restore of `db` as to be redone and forgets the source row
+/// counts and the phases after the restore, because the dump they worked
+/// from is being replaced.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be updated.
+pub fn dump_replaced(config: &Config, db: &str) -> Result<()> {
+    update_database(config, db, |r| {
+        if let Some(restore) = &mut r.restore {
+            restore.error = Some("the dump it restored was replaced".to_string());
+        }
+        r.source_counts = None;
+        r.roles_reassigned = false;
+        r.update_extensions = None;
+        r.extension_updates.clear();
+        r.collations = None;
+        r.collation_changes.clear();
+        r.analyze = None;
+        r.verify = None;
+    })
+}
+
+/// Applies `f` to the record of `db`.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be updated.
+pub fn update_database(
+    config: &Config,
+    db: &str,
+    f: impl FnOnce(&mut DatabaseRecord),
+) -> Result<()> {
+    update(config, |m| f(m.database_mut(config, db)))
+}
+
+/// Records the outcome of the globals migration.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be updated.
+pub fn globals_finished(config: &Config, started_at: u64, result: &Result<()>) -> Result<()> {
+    update(config, |m| {
+        let mut record = PhaseRecord {
+            started_at,
+            ..PhaseRecord::default()
+        };
+        record.finish(result);
+        m.globals = Some(record);
+    })
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+#[must_use]
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
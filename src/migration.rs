@@ -0,0 +1,417 @@
+use crate::commands::{self, DryRunScope};
+use crate::config::{Config, DatabaseOverride, ScheduleOrder};
+use crate::observer::{MigrationObserver, NoopObserver};
+use anyhow::Result;
+use std::{
+    future::{Future, IntoFuture},
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Connection details for one side of a migration.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub pass: String,
+    /// Maintenance database used for discovery and server-level statements.
+    pub db: String,
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Self {
+            host: "localhost".into(),
+            port: 5432,
+            user: "postgres".into(),
+            pass: String::new(),
+            db: "postgres".into(),
+        }
+    }
+}
+
+impl Endpoint {
+    /// Creates an endpoint for `host` with the default port, user and database.
+    #[must_use]
+    pub fn new(host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub const fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    #[must_use]
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = user.into();
+        self
+    }
+
+    #[must_use]
+    pub fn pass(mut self, pass: impl Into<String>) -> Self {
+        self.pass = pass.into();
+        self
+    }
+
+    #[must_use]
+    pub fn db(mut self, db: impl Into<String>) -> Self {
+        self.db = db.into();
+        self
+    }
+}
+
+/// A fully configured migration, ready to be started.
+///
+/// ```no_run
+/// use pg_migrate::{Endpoint, Migration};
+///
+/// # async fn example() -> anyhow::Result<()> {
+/// let handle = Migration::builder()
+///     .source(Endpoint::new("old-db").pass("secret"))
+///     .target(Endpoint::new("new-db").pass("secret"))
+///     .include_db("app_*")
+///     .rename_db("app", "app_v2")
+///     .build()?
+///     .start();
+///
+/// handle.await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Migration {
+    config: Config,
+    observer: Arc<dyn MigrationObserver>,
+    cancel: CancellationToken,
+}
+
+impl Migration {
+    #[must_use]
+    pub fn builder() -> MigrationBuilder {
+        MigrationBuilder::default()
+    }
+
+    #[must_use]
+    pub const fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Runs every phase to completion on the current task.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any phase fails or the migration is cancelled.
+    pub async fn run(self) -> Result<()> {
+        if self.config.dry_run {
+            return commands::dry_run(&self.config, DryRunScope::ALL).await;
+        }
+        commands::run(&self.config, &self.observer, &self.cancel).await
+    }
+
+    /// Spawns the migration onto the Tokio runtime and returns a handle to it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime.
+    #[must_use]
+    pub fn start(self) -> MigrationHandle {
+        let cancel = self.cancel.clone();
+        MigrationHandle {
+            cancel,
+            task: tokio::spawn(self.run()),
+        }
+    }
+}
+
+/// Handle to a migration running in the background.
+///
+/// Awaiting the handle waits for the migration to finish.
+pub struct MigrationHandle {
+    cancel: CancellationToken,
+    task: JoinHandle<Result<()>>,
+}
+
+impl MigrationHandle {
+    /// Asks the migration to stop; running `pg_dump`/`pg_restore` children are killed.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    #[must_use]
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    #[must_use]
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl IntoFuture for MigrationHandle {
+    type Output = Result<()>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(async move { self.task.await? })
+    }
+}
+
+/// Builder for [`Migration`]; unset options keep the CLI defaults.
+#[derive(Default)]
+pub struct MigrationBuilder {
+    config: Config,
+    observer: Option<Arc<dyn MigrationObserver>>,
+    cancel: Option<CancellationToken>,
+}
+
+impl MigrationBuilder {
+    #[must_use]
+    pub fn source(mut self, endpoint: Endpoint) -> Self {
+        self.config.from_host = endpoint.host;
+        self.config.from_port = endpoint.port.to_string();
+        self.config.from_user = endpoint.user;
+        self.config.from_pass = endpoint.pass;
+        self.config.from_db = endpoint.db;
+        self
+    }
+
+    #[must_use]
+    pub fn target(mut self, endpoint: Endpoint) -> Self {
+        self.config.to_host = endpoint.host;
+        self.config.to_port = endpoint.port.to_string();
+        self.config.to_user = endpoint.user;
+        self.config.to_pass = endpoint.pass;
+        self.config.to_db = endpoint.db;
+        self
+    }
+
+    /// Only migrate databases matching `pattern` (glob, or `re:<regex>`).
+    #[must_use]
+    pub fn include_db(mut self, pattern: impl Into<String>) -> Self {
+        self.config.include_dbs.push(pattern.into());
+        self
+    }
+
+    /// Never migrate databases matching `pattern` (glob, or `re:<regex>`).
+    #[must_use]
+    pub fn exclude_db(mut self, pattern: impl Into<String>) -> Self {
+        self.config.exclude_dbs.push(pattern.into());
+        self
+    }
+
+    #[must_use]
+    pub const fn include_postgres_db(mut self, include: bool) -> Self {
+        self.config.include_postgres_db = include;
+        self
+    }
+
+    /// Restores the source database `from` as `to` on the target.
+    #[must_use]
+    pub fn rename_db(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.config.databases.entry(from.into()).or_default().target = Some(to.into());
+        self
+    }
+
+    /// Leaves roles matching `pattern` (glob, or `re:<regex>`) out of the globals.
+    #[must_use]
+    pub fn exclude_role(mut self, pattern: impl Into<String>) -> Self {
+        self.config.exclude_roles.push(pattern.into());
+        self
+    }
+
+    /// Gives the source role `from` the name `to` on the target.
+    #[must_use]
+    pub fn rename_role(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.config.role_renames.insert(from.into(), to.into());
+        self
+    }
+
+    /// Sets per-database overrides, replacing any set earlier for `db`.
+    #[must_use]
+    pub fn database(mut self, db: impl Into<String>, overrides: DatabaseOverride) -> Self {
+        self.config.databases.insert(db.into(), overrides);
+        self
+    }
+
+    /// Sets both the `pg_dump` and `pg_restore` job counts.
+    #[must_use]
+    pub const fn jobs(mut self, jobs: usize) -> Self {
+        self.config.dump_jobs = jobs;
+        self.config.restore_jobs = jobs;
+        self
+    }
+
+    #[must_use]
+    pub const fn dump_jobs(mut self, jobs: usize) -> Self {
+        self.config.dump_jobs = jobs;
+        self
+    }
+
+    #[must_use]
+    pub const fn restore_jobs(mut self, jobs: usize) -> Self {
+        self.config.restore_jobs = jobs;
+        self
+    }
+
+    #[must_use]
+    pub const fn max_parallel(mut self, max_parallel: usize) -> Self {
+        self.config.max_parallel = max_parallel;
+        self
+    }
+
+    #[must_use]
+    pub fn compression(mut self, compression: impl Into<String>) -> Self {
+        self.config.compression = compression.into();
+        self
+    }
+
+    /// Caps the `pg_dump`/`pg_restore` jobs of all running databases at
+    /// `budget`, shared out in proportion to database size.
+    #[must_use]
+    pub const fn jobs_budget(mut self, budget: usize) -> Self {
+        self.config.jobs_budget = Some(budget);
+        self
+    }
+
+    #[must_use]
+    pub const fn order(mut self, order: ScheduleOrder) -> Self {
+        self.config.order = order;
+        self
+    }
+
+    /// Starts databases matching `pattern` (glob, or `re:<regex>`) before the
+    /// others; earlier patterns go first.
+    #[must_use]
+    pub fn priority_db(mut self, pattern: impl Into<String>) -> Self {
+        self.config.priority_dbs.push(pattern.into());
+        self
+    }
+
+    #[must_use]
+    pub fn dump_root(mut self, dump_root: impl Into<PathBuf>) -> Self {
+        self.config.dump_root = dump_root.into();
+        self
+    }
+
+    /// Keeps run state under `state_dir` instead of `$HOME/pg_migrate_state`.
+    #[must_use]
+    pub fn state_dir(mut self, state_dir: impl Into<PathBuf>) -> Self {
+        self.config.state_root = Some(state_dir.into());
+        self
+    }
+
+    /// Names the run's state directory instead of deriving it from the endpoints.
+    #[must_use]
+    pub fn run_id(mut self, run_id: impl Into<String>) -> Self {
+        self.config.run_id = Some(run_id.into());
+        self
+    }
+
+    #[must_use]
+    pub const fn migrate_globals(mut self, migrate_globals: bool) -> Self {
+        self.config.migrate_globals = migrate_globals;
+        self
+    }
+
+    #[must_use]
+    pub const fn disable_dst_optimizations(mut self, disable: bool) -> Self {
+        self.config.disable_dst_optimizations = disable;
+        self
+    }
+
+    /// Selects the fast restore profile by name.
+    #[must_use]
+    pub fn fast_restore_profile(mut self, profile: impl Into<String>) -> Self {
+        self.config.fast_restore_profile = profile.into();
+        self
+    }
+
+    /// Pipes `pg_dump` straight into `pg_restore` instead of writing dumps under `dump_root`.
+    #[must_use]
+    pub const fn stream(mut self, stream: bool) -> Self {
+        self.config.stream = stream;
+        self
+    }
+
+    /// Runs `ALTER EXTENSION ... UPDATE` in every database after its restore.
+    #[must_use]
+    pub const fn update_extensions(mut self, update: bool) -> Self {
+        self.config.update_extensions = update;
+        self
+    }
+
+    /// Runs `vacuumdb --analyze-in-stages` in every database after its restore.
+    #[must_use]
+    pub const fn analyze(mut self, analyze: bool) -> Self {
+        self.config.analyze = analyze;
+        self
+    }
+
+    /// Rebuilds indexes on collations whose version changed after the restore.
+    #[must_use]
+    pub const fn reindex_collations(mut self, reindex: bool) -> Self {
+        self.config.reindex_collations = reindex;
+        self
+    }
+
+    /// Goes ahead even if the compatibility scan finds objects the target
+    /// cannot restore.
+    #[must_use]
+    pub const fn ignore_incompatible(mut self, ignore: bool) -> Self {
+        self.config.ignore_incompatible = ignore;
+        self
+    }
+
+    /// Prints the plan (see [`commands::dry_run`]) instead of running the migration.
+    #[must_use]
+    pub const fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    /// Sends progress events to `observer` instead of discarding them.
+    #[must_use]
+    pub fn observer(mut self, observer: Arc<dyn MigrationObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Uses an existing token, e.g. one shared with the embedding service's
+    /// shutdown logic, instead of creating a fresh one.
+    #[must_use]
+    pub fn cancellation_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Starts from an already assembled [`Config`], e.g. one loaded from a file.
+    #[must_use]
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Validates the options and returns the migration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a database or role filter pattern is invalid or
+    /// the fast restore profile is unknown.
+    pub fn build(self) -> Result<Migration> {
+        self.config.validate()?;
+        Ok(Migration {
+            config: self.config,
+            observer: self.observer.unwrap_or_else(|| Arc::new(NoopObserver)),
+            cancel: self.cancel.unwrap_or_default(),
+        })
+    }
+}
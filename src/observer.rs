@@ -0,0 +1,74 @@
+use crate::compat::Finding;
+use crate::extensions::ExtensionIssue;
+use crate::verification::VerificationResult;
+
+/// A per-database step of the migration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Dump,
+    Restore,
+    /// `ALTER EXTENSION ... UPDATE` after the restore.
+    UpdateExtensions,
+    /// Collation version check and optional reindex after the restore.
+    Collations,
+    /// `vacuumdb --analyze-in-stages` after the restore.
+    Analyze,
+    Verify,
+}
+
+impl Phase {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Dump => "dump",
+            Self::Restore => "restore",
+            Self::UpdateExtensions => "update_extensions",
+            Self::Collations => "collations",
+            Self::Analyze => "analyze",
+            Self::Verify => "verify",
+        }
+    }
+}
+
+/// Receives progress events while a migration runs.
+///
+/// Every method has an empty default so implementations only override what
+/// they care about. Events for different databases arrive concurrently from
+/// several tasks, hence the `Send + Sync` bound.
+pub trait MigrationObserver: Send + Sync {
+    /// The databases selected for migration, with their source sizes.
+    fn databases_discovered(&self, _dbs_with_sizes: &[(String, u64)]) {}
+
+    /// `phase` started for `db`; `size` is the source size in bytes.
+    fn phase_started(&self, _db: &str, _phase: Phase, _size: u64) {}
+
+    fn phase_finished(&self, _db: &str, _phase: Phase) {}
+
+    /// `phase` was not run for `db` because an earlier run already did it.
+    fn phase_skipped(&self, _db: &str, _phase: Phase) {}
+
+    /// `bytes` were produced or consumed by `phase` for `db`.
+    fn bytes(&self, _db: &str, _phase: Phase, _bytes: u64) {}
+
+    /// `done` of `total` tables have been counted in `db` (source or target).
+    fn tables(&self, _db: &str, _done: usize, _total: usize) {}
+
+    /// Something went wrong but the migration carries on.
+    fn warning(&self, _db: Option<&str>, _message: &str) {}
+
+    /// Extensions of `db` have been compared with what the target provides;
+    /// `issues` is empty if all of them come over unchanged.
+    fn extensions(&self, _db: &str, _issues: &[ExtensionIssue]) {}
+
+    /// `db` has been scanned for objects the target version cannot take;
+    /// `findings` is empty if there are none.
+    fn incompatibilities(&self, _db: &str, _findings: &[Finding]) {}
+
+    /// Row counts of `db` have been compared between source and target.
+    fn verification(&self, _db: &str, _result: &VerificationResult) {}
+}
+
+/// Observer that ignores every event.
+pub struct NoopObserver;
+
+impl MigrationObserver for NoopObserver {}
@@ -1,175 +1,271 @@
-use crate::{Config, db, verification};
-use indicatif::ProgressBar;
+use crate::observer::{MigrationObserver, Phase};
+use crate::schedule::JobsBudget;
+use crate::{Config, collations, db, extensions, manifest, roles, verification};
 use log::info;
-use std::collections::HashMap;
-use std::fs;
 use std::sync::Arc;
 use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
 
+/// Dumps every database, at most `sem` permits at a time.
+///
+/// # Errors
+///
+/// Returns the first dump error, or a cancellation error.
 pub async fn phase_dump_all(
     config: &Config,
     dbs_with_sizes: &[(String, u64)],
-    pbs: &HashMap<String, ProgressBar>,
+    observer: &Arc<dyn MigrationObserver>,
     cancel: &CancellationToken,
     sem: Arc<Semaphore>,
 ) -> anyhow::Result<()> {
-    let mut dump_tasks = vec![];
+    let budget = JobsBudget::new(config);
+    let config = Arc::new(config.clone());
 
-    for (db, size) in dbs_with_sizes {
-        let permit = sem.clone().acquire_owned().await?;
-        let pb = pbs.get(db).cloned().expect("missing pb");
-        let config_clone = Arc::new(Config {
-            from_host: config.from_host.clone(),
-            from_port: config.from_port.clone(),
-            from_user: config.from_user.clone(),
-            from_pass: config.from_pass.clone(),
-            from_db: config.from_db.clone(),
-            to_host: config.to_host.clone(),
-            to_port: config.to_port.clone(),
-            to_user: config.to_user.clone(),
-            to_pass: config.to_pass.clone(),
-            to_db: config.to_db.clone(),
-            dump_jobs: config.dump_jobs,
-            restore_jobs: config.restore_jobs,
-            max_parallel: config.max_parallel,
-            dump_root: config.dump_root.clone(),
-            migrate_globals: config.migrate_globals,
-            disable_dst_optimizations: config.disable_dst_optimizations,
-        });
-        let cancel_clone = cancel.clone();
-        let db_clone = db.clone();
-        let size_val = *size;
-
-        dump_tasks.push(tokio::spawn(async move {
-            let _p = permit;
-            db::dump_db(&config_clone, &db_clone, size_val, pb, cancel_clone).await
-        }));
-    }
-
-    for dump_task in dump_tasks {
-        match dump_task.await? {
-            Ok(()) => {}
-            Err(e) => {
-                if cancel.is_cancelled() {
-                    anyhow::bail!("Migration cancelled by user");
-                }
-                return Err(e);
-            }
+    for_each_db(dbs_with_sizes, cancel, sem, |db, size| {
+        let observer = observer.clone();
+        let config = config.clone();
+        let cancel = cancel.clone();
+        let budget = budget.clone();
+        async move {
+            let _jobs = match &budget {
+                Some(budget) => Some(budget.acquire(&config, &db).await?),
+                None => None,
+            };
+            db::dump_db(&config, &db, size, &*observer, cancel).await
         }
-    }
-    Ok(())
+    })
+    .await
 }
 
+/// Records source row counts for every database that does not have them yet.
+///
+/// # Errors
+///
+/// Returns an error if a source database cannot be counted.
 pub async fn phase_compute_source_counts(
     config: &Config,
     db_names: &[String],
+    observer: &dyn MigrationObserver,
 ) -> anyhow::Result<()> {
     for db in db_names {
-        let src_path = verification::src_counts_path(db);
-
-        if !src_path.exists() {
-            let counts = verification::stat_counts(
-                &config.from_host,
-                &config.from_port,
-                &config.from_pass,
-                &config.from_user,
-                db,
-            )
-            .await?;
-            let content = serde_json::to_string(&counts)?;
-            fs::write(&src_path, content)?;
-        }
+        verification::source_counts(config, db, observer).await?;
     }
     Ok(())
 }
 
+/// Restores every database not restored yet and runs the post-restore steps
+/// still due, at most `sem` permits at a time.
+///
+/// # Errors
+///
+/// Returns the first restore error, or a cancellation error.
 pub async fn phase_restore_all(
     config: &Config,
     dbs_with_sizes: &[(String, u64)],
-    pbs: &HashMap<String, ProgressBar>,
+    observer: &Arc<dyn MigrationObserver>,
     cancel: &CancellationToken,
     sem: Arc<Semaphore>,
 ) -> anyhow::Result<()> {
-    let mut restore_tasks = vec![];
+    let budget = JobsBudget::new(config);
+    let manifest = manifest::load(config)?;
+    let config = Arc::new(config.clone());
 
-    for (db, size) in dbs_with_sizes {
-        if db::done_marker(db).exists() {
-            info!("Skipping restore for {db}");
-            if let Some(pb) = pbs.get(db) {
-                pb.set_position(size.saturating_mul(2));
-                pb.set_message(format!("Restoration skipped (already done) for {db}"));
+    for_each_db(dbs_with_sizes, cancel, sem, |db, size| {
+        let restored = manifest.is_finished(&config, &db, Phase::Restore);
+        let observer = observer.clone();
+        let config = config.clone();
+        let cancel = cancel.clone();
+        let budget = budget.clone();
+        async move {
+            let _jobs = match &budget {
+                Some(budget) => Some(budget.acquire(&config, &db).await?),
+                None => None,
+            };
+            if restored {
+                info!("Skipping restore for {db}");
+                observer.phase_skipped(&db, Phase::Restore);
+            } else {
+                db::restore_db(&config, &db, size, &*observer, cancel.clone()).await?;
             }
-            continue;
+            post_restore(&config, &db, &*observer, cancel).await
         }
+    })
+    .await
+}
 
+/// Takes every database through dump, source count, restore and verify,
+/// at most `sem` permits at a time.
+///
+/// Each database moves on to its restore as soon as its own dump is done,
+/// while the other databases are still being dumped. With `stream` set the
+/// dump and restore are one piped step.
+///
+/// # Errors
+///
+/// Returns the first error of any database, or a cancellation error.
+pub async fn phase_pipeline_all(
+    config: &Config,
+    dbs_with_sizes: &[(String, u64)],
+    observer: &Arc<dyn MigrationObserver>,
+    cancel: &CancellationToken,
+    sem: Arc<Semaphore>,
+) -> anyhow::Result<()> {
+    let budget = JobsBudget::new(config);
+    let config = Arc::new(config.clone());
+
+    for_each_db(dbs_with_sizes, cancel, sem, |db, size| {
+        let observer = observer.clone();
+        let config = config.clone();
+        let cancel = cancel.clone();
+        let budget = budget.clone();
+        async move {
+            let _jobs = match &budget {
+                Some(budget) => Some(budget.acquire(&config, &db).await?),
+                None => None,
+            };
+            pipeline_db(&config, &db, size, &*observer, cancel).await
+        }
+    })
+    .await
+}
+
+/// Runs `task` for every database, at most `sem` permits at a time.
+///
+/// On the first error no more tasks are started, and the ones still running
+/// are aborted and awaited before returning, so no `pg_dump` or `pg_restore`
+/// outlives the phase.
+async fn for_each_db<F, Fut>(
+    dbs_with_sizes: &[(String, u64)],
+    cancel: &CancellationToken,
+    sem: Arc<Semaphore>,
+    task: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(String, u64) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let mut tasks = JoinSet::new();
+    for (db, size) in dbs_with_sizes {
         let permit = sem.clone().acquire_owned().await?;
-        let pb = pbs.get(db).cloned().expect("missing pb");
-        let config_clone = Arc::new(Config {
-            from_host: config.from_host.clone(),
-            from_port: config.from_port.clone(),
-            from_user: config.from_user.clone(),
-            from_pass: config.from_pass.clone(),
-            from_db: config.from_db.clone(),
-            to_host: config.to_host.clone(),
-            to_port: config.to_port.clone(),
-            to_user: config.to_user.clone(),
-            to_pass: config.to_pass.clone(),
-            to_db: config.to_db.clone(),
-            dump_jobs: config.dump_jobs,
-            restore_jobs: config.restore_jobs,
-            max_parallel: config.max_parallel,
-            dump_root: config.dump_root.clone(),
-            migrate_globals: config.migrate_globals,
-            disable_dst_optimizations: config.disable_dst_optimizations,
+        // A failed task gives its permit back, so it is seen here
+        while let Some(joined) = tasks.try_join_next() {
+            if let Err(e) = joined.map_err(anyhow::Error::from).and_then(|r| r) {
+                return stop(tasks, cancel, e).await;
+            }
+        }
+        let work = task(db.clone(), *size);
+        tasks.spawn(async move {
+            let _p = permit;
+            work.await
         });
-        let cancel_clone = cancel.clone();
-        let db_clone = db.clone();
-        let size_val = *size;
+    }
 
-        restore_tasks.push(tokio::spawn(async move {
-            let _p = permit;
-            db::restore_db(&config_clone, &db_clone, size_val, pb, cancel_clone).await
-        }));
+    while let Some(joined) = tasks.join_next().await {
+        if let Err(e) = joined.map_err(anyhow::Error::from).and_then(|r| r) {
+            return stop(tasks, cancel, e).await;
+        }
     }
+    Ok(())
+}
 
-    for restore_task in restore_tasks {
-        match restore_task.await? {
-            Ok(()) => {}
-            Err(e) => {
-                if cancel.is_cancelled() {
-                    anyhow::bail!("Migration cancelled by user");
-                }
-                return Err(e);
-            }
+/// Aborts the remaining tasks, waits for them and returns `e`, or a
+/// cancellation error if the user cancelled.
+async fn stop(
+    mut tasks: JoinSet<anyhow::Result<()>>,
+    cancel: &CancellationToken,
+    e: anyhow::Error,
+) -> anyhow::Result<()> {
+    tasks.shutdown().await;
+    if cancel.is_cancelled() {
+        anyhow::bail!("Migration cancelled by user");
+    }
+    Err(e)
+}
+
+async fn pipeline_db(
+    config: &Config,
+    db: &str,
+    size: u64,
+    observer: &dyn MigrationObserver,
+    cancel: CancellationToken,
+) -> anyhow::Result<()> {
+    let manifest = manifest::load(config)?;
+    let restored = manifest.is_finished(config, db, Phase::Restore);
+    let verified = manifest.is_finished(config, db, Phase::Verify);
+
+    // A database that is already restored and verified needs no dump
+    if restored && verified {
+        observer.phase_skipped(db, Phase::Dump);
+    } else if !config.stream {
+        db::dump_db(config, db, size, observer, cancel.clone()).await?;
+    }
+    verification::source_counts(config, db, observer).await?;
+
+    // A redump above may have invalidated the restore
+    if manifest::load(config)?.is_finished(config, db, Phase::Restore) {
+        info!("Skipping restore for {db}");
+        observer.phase_skipped(db, Phase::Restore);
+    } else if config.stream {
+        db::stream_db(config, db, size, observer, cancel.clone()).await?;
+    } else {
+        db::restore_db(config, db, size, observer, cancel.clone()).await?;
+    }
+
+    post_restore(config, db, observer, cancel).await?;
+
+    if manifest::load(config)?.is_finished(config, db, Phase::Verify) {
+        observer.phase_skipped(db, Phase::Verify);
+        return Ok(());
+    }
+    verification::verify_db(config, db, observer).await
+}
+
+/// Runs the post-restore steps for `db` that have not been done yet: the
+/// hand-over to renamed roles, the optional extension update, the collation
+/// check, then the optional `ANALYZE`.
+async fn post_restore(
+    config: &Config,
+    db: &str,
+    observer: &dyn MigrationObserver,
+    cancel: CancellationToken,
+) -> anyhow::Result<()> {
+    roles::reassign_db(config, db).await?;
+    let manifest = manifest::load(config)?;
+    if config.update_extensions {
+        if manifest.is_finished(config, db, Phase::UpdateExtensions) {
+            observer.phase_skipped(db, Phase::UpdateExtensions);
+        } else {
+            extensions::update_db(config, db, observer).await?;
+        }
+    }
+    if manifest.is_finished(config, db, Phase::Collations) {
+        observer.phase_skipped(db, Phase::Collations);
+    } else {
+        collations::check_db(config, db, observer).await?;
+    }
+    if config.analyze {
+        if manifest.is_finished(config, db, Phase::Analyze) {
+            observer.phase_skipped(db, Phase::Analyze);
+        } else {
+            db::analyze_db(config, db, observer, cancel).await?;
         }
     }
     Ok(())
 }
 
+/// Records target row counts and verifies every database against the source.
+///
+/// # Errors
+///
+/// Returns an error if counting fails or any database does not match.
 pub async fn phase_verify_all(
     config: &Config,
     db_names: &[String],
-    pbs: &HashMap<String, ProgressBar>,
+    observer: &dyn MigrationObserver,
 ) -> anyhow::Result<()> {
     for db in db_names {
-        let pb = pbs.get(db).cloned().expect("missing pb");
-        let dst_path = verification::dst_counts_path(db);
-
-        if !dst_path.exists() {
-            let counts = verification::stat_counts(
-                &config.to_host,
-                &config.to_port,
-                &config.to_pass,
-                &config.to_user,
-                db,
-            )
-            .await?;
-
-            let content = serde_json::to_string(&counts)?;
-            fs::write(&dst_path, content)?;
-        }
-        verification::verify_db(config, db, pb).await?;
+        verification::verify_db(config, db, observer).await?;
     }
     Ok(())
 }
@@ -0,0 +1,427 @@
+use crate::compat::{self, Severity, Versions};
+use crate::extensions::{self, TargetExtensions};
+use crate::{Config, db};
+use anyhow::{Context, Result};
+use indicatif::HumanBytes;
+use sqlx::PgPool;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+use tokio::process::Command;
+
+/// Outcome of a single preflight check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl CheckStatus {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        }
+    }
+}
+
+/// One line of the preflight report.
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// What preflight learned about one server.
+struct Server {
+    pool: PgPool,
+    major: u32,
+}
+
+/// Runs every preflight check without writing any state.
+///
+/// Checks that cannot run because an earlier one failed (e.g. privileges
+/// without a connection) are left out of the report.
+pub async fn run_checks(config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let source = connect(
+        &mut checks,
+        "source connection",
+        [
+            &config.from_host,
+            &config.from_port,
+            &config.from_user,
+            &config.from_pass,
+            &config.from_db,
+        ],
+    )
+    .await;
+    let target = connect(
+        &mut checks,
+        "target connection",
+        [
+            &config.to_host,
+            &config.to_port,
+            &config.to_user,
+            &config.to_pass,
+            &config.to_db,
+        ],
+    )
+    .await;
+
+    if let Some(source) = &source {
+        checks.push(source_privileges(config, &source.pool).await);
+    }
+    if let Some(target) = &target {
+        checks.push(target_privileges(config, &target.pool).await);
+    }
+
+    let source_major = source.as_ref().map(|s| s.major);
+    let target_major = target.as_ref().map(|s| s.major);
+    let dump_major = tool_check(&mut checks, "pg_dump", true, source_major, target_major).await;
+    tool_check(&mut checks, "pg_restore", true, dump_major, target_major).await;
+    tool_check(
+        &mut checks,
+        "pg_dumpall",
+        config.migrate_globals,
+        source_major,
+        target_major,
+    )
+    .await;
+    if config.analyze {
+        tool_check(&mut checks, "vacuumdb", true, None, target_major).await;
+    }
+
+    if source.is_some() {
+        match db::discover_databases(config).await {
+            Ok(dbs) => {
+                let total: u64 = dbs.iter().map(|(_, s)| s).sum();
+                checks.push(Check::new(
+                    "discovery",
+                    CheckStatus::Pass,
+                    format!("{} databases, {} total", dbs.len(), HumanBytes(total)),
+                ));
+                checks.push(disk_space(config, total).await);
+                if target.is_some() {
+                    checks.push(compatibility(config, &dbs).await);
+                    checks.push(extensions(config, &dbs).await);
+                }
+            }
+            Err(e) => checks.push(Check::new("discovery", CheckStatus::Fail, format!("{e:#}"))),
+        }
+    }
+    checks
+}
+
+async fn connect(
+    checks: &mut Vec<Check>,
+    name: &str,
+    [host, port, user, pass, db]: [&String; 5],
+) -> Option<Server> {
+    let connected = async {
+        let pool = db::pg_pool(host, port, user, pass, db).await?;
+        let version: String = sqlx::query_scalar("SHOW server_version_num")
+            .fetch_one(&pool)
+            .await?;
+        let num: u32 = version.parse().context("unexpected server_version_num")?;
+        anyhow::Ok((pool, num))
+    };
+    match connected.await {
+        Ok((pool, num)) => {
+            let major = num / 10000;
+            checks.push(Check::new(
+                name,
+                CheckStatus::Pass,
+                format!("{user}@{host}:{port}/{db}, PostgreSQL {major}"),
+            ));
+            Some(Server { pool, major })
+        }
+        Err(e) => {
+            checks.push(Check::new(
+                name,
+                CheckStatus::Fail,
+                format!("{user}@{host}:{port}/{db}: {e:#}"),
+            ));
+            None
+        }
+    }
+}
+
+/// Returns `(rolsuper, rolcreatedb)` of the connected user.
+async fn role_flags(pool: &PgPool) -> Result<(bool, bool)> {
+    Ok(
+        sqlx::query_as("SELECT rolsuper, rolcreatedb FROM pg_roles WHERE rolname = current_user")
+            .fetch_one(pool)
+            .await?,
+    )
+}
+
+async fn source_privileges(config: &Config, pool: &PgPool) -> Check {
+    let name = "source privileges";
+    match role_flags(pool).await {
+        Ok((true, _)) => Check::new(name, CheckStatus::Pass, "superuser"),
+        Ok((false, _)) if config.migrate_globals => Check::new(
+            name,
+            CheckStatus::Fail,
+            format!(
+                "{} is not a superuser; pg_dumpall cannot read role passwords (or use --migrate-globals false)",
+                config.from_user
+            ),
+        ),
+        Ok((false, _)) => Check::new(
+            name,
+            CheckStatus::Warn,
+            format!(
+                "{} is not a superuser; pg_dump may miss objects it cannot read",
+                config.from_user
+            ),
+        ),
+        Err(e) => Check::new(name, CheckStatus::Fail, format!("{e:#}")),
+    }
+}
+
+async fn target_privileges(config: &Config, pool: &PgPool) -> Check {
+    let name = "target privileges";
+    match role_flags(pool).await {
+        Ok((true, _)) => Check::new(name, CheckStatus::Pass, "superuser"),
+        Ok((false, false)) => Check::new(
+            name,
+            CheckStatus::Fail,
+            format!(
+                "{} can neither create databases nor is a superuser",
+                config.to_user
+            ),
+        ),
+        Ok((false, true)) => Check::new(
+            name,
+            CheckStatus::Warn,
+            format!(
+                "{} is not a superuser; roles and ownership may not be restorable{}",
+                config.to_user,
+                if config.disable_dst_optimizations {
+                    ""
+                } else {
+                    ", and without ALTER SYSTEM only the pg_restore sessions are tuned"
+                }
+            ),
+        ),
+        Err(e) => Check::new(name, CheckStatus::Fail, format!("{e:#}")),
+    }
+}
+
+/// Checks that `program` is on `PATH` and can talk to servers of the given
+/// major versions; returns its own major version.
+///
+/// The tool must be at least as new as `min_major` (the server it reads from,
+/// or the `pg_dump` that wrote the archive). A tool newer than `target_major`
+/// may emit syntax the target does not understand, which is only a warning.
+async fn tool_check(
+    checks: &mut Vec<Check>,
+    program: &str,
+    required: bool,
+    min_major: Option<u32>,
+    target_major: Option<u32>,
+) -> Option<u32> {
+    let missing = if required {
+        CheckStatus::Fail
+    } else {
+        CheckStatus::Warn
+    };
+    let Some(path) = find_on_path(program) else {
+        checks.push(Check::new(program, missing, "not found on PATH"));
+        return None;
+    };
+    let Some(version) = db::tool_version(program).await else {
+        checks.push(Check::new(
+            program,
+            missing,
+            format!("{} --version failed", path.display()),
+        ));
+        return None;
+    };
+    let major = major_version(&version);
+    let detail = format!("{} ({version})", path.display());
+
+    let check = match (major, min_major, target_major) {
+        (Some(major), Some(min), _) if major < min => Check::new(
+            program,
+            CheckStatus::Fail,
+            format!("{detail}: older than {min}, upgrade the client tools"),
+        ),
+        (Some(major), _, Some(target)) if major > target => Check::new(
+            program,
+            CheckStatus::Warn,
+            format!("{detail}: newer than target {target}, output may not load there"),
+        ),
+        (None, _, _) => Check::new(
+            program,
+            CheckStatus::Warn,
+            format!("{detail}: unknown version"),
+        ),
+        _ => Check::new(program, CheckStatus::Pass, detail),
+    };
+    checks.push(check);
+    major
+}
+
+/// Returns the first `program` found in the directories of `PATH`.
+#[must_use]
+pub fn find_on_path(program: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|p| p.is_file())
+}
+
+/// Extracts the major version from `--version` output such as
+/// `pg_dump (PostgreSQL) 15.4 (Debian 15.4-1)`.
+#[must_use]
+pub fn major_version(version: &str) -> Option<u32> {
+    version
+        .split_whitespace()
+        .find(|t| t.starts_with(|c: char| c.is_ascii_digit()))?
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Summarises the compatibility scan of every database; the details are
+/// printed by `run` and `dump`.
+async fn compatibility(config: &Config, dbs_with_sizes: &[(String, u64)]) -> Check {
+    let name = "compatibility";
+    let scanned = async {
+        let versions = Versions::load(config).await?;
+        let mut findings = Vec::new();
+        for (db, _) in dbs_with_sizes {
+            findings.extend(compat::scan_db(config, db, versions).await?);
+        }
+        anyhow::Ok((versions, findings))
+    };
+    match scanned.await {
+        Ok((versions, findings)) => {
+            let fatal = findings
+                .iter()
+                .filter(|f| f.severity == Severity::Fatal)
+                .count();
+            let detail = format!(
+                "PostgreSQL {} -> {}: {fatal} fatal, {} warnings",
+                versions.source,
+                versions.target,
+                findings.len() - fatal
+            );
+            let status = if fatal > 0 && !config.ignore_incompatible {
+                CheckStatus::Fail
+            } else if findings.is_empty() {
+                CheckStatus::Pass
+            } else {
+                CheckStatus::Warn
+            };
+            Check::new(name, status, detail)
+        }
+        Err(e) => Check::new(name, CheckStatus::Fail, format!("{e:#}")),
+    }
+}
+
+/// Summarises the extension report of every database; the details are
+/// printed by `run` and `restore`.
+async fn extensions(config: &Config, dbs_with_sizes: &[(String, u64)]) -> Check {
+    let name = "extensions";
+    let checked = async {
+        let target = TargetExtensions::load(config).await?;
+        let mut fatal = Vec::new();
+        let mut other = Vec::new();
+        for (db, _) in dbs_with_sizes {
+            for issue in extensions::check_db(config, db, &target).await? {
+                let line = format!("{db}: {} {}", issue.extension, issue.problem);
+                if issue.is_fatal() {
+                    fatal.push(line);
+                } else {
+                    other.push(line);
+                }
+            }
+        }
+        anyhow::Ok((fatal, other))
+    };
+    match checked.await {
+        Ok((fatal, _)) if !fatal.is_empty() => {
+            Check::new(name, CheckStatus::Fail, fatal.join("; "))
+        }
+        Ok((_, other)) if !other.is_empty() => {
+            Check::new(name, CheckStatus::Warn, other.join("; "))
+        }
+        Ok(_) => Check::new(name, CheckStatus::Pass, "all available on the target"),
+        Err(e) => Check::new(name, CheckStatus::Fail, format!("{e:#}")),
+    }
+}
+
+async fn disk_space(config: &Config, needed: u64) -> Check {
+    let name = "disk space";
+    if config.stream {
+        return Check::new(name, CheckStatus::Pass, "not needed, streaming");
+    }
+    let path = existing_ancestor(&config.dump_root);
+    let free = match free_bytes(&path).await {
+        Ok(free) => free,
+        Err(e) => return Check::new(name, CheckStatus::Warn, format!("{e:#}")),
+    };
+    let detail = format!(
+        "{} free under {}, databases total {}",
+        HumanBytes(free),
+        path.display(),
+        HumanBytes(needed)
+    );
+    // Compressed dumps without indexes are usually far smaller than the
+    // databases, so only a large shortfall is fatal
+    let status = if free >= needed {
+        CheckStatus::Pass
+    } else if free >= needed / 4 {
+        CheckStatus::Warn
+    } else {
+        CheckStatus::Fail
+    };
+    Check::new(name, status, detail)
+}
+
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|p| p.exists())
+        .filter(|p| !p.as_os_str().is_empty())
+        .map_or_else(|| PathBuf::from("."), Path::to_path_buf)
+}
+
+/// Returns the free bytes on the filesystem holding `path`, using `df`.
+async fn free_bytes(path: &Path) -> Result<u64> {
+    let output = Command::new("df")
+        .arg("-Pk")
+        .arg(path)
+        .output()
+        .await
+        .context("could not run df")?;
+    if !output.status.success() {
+        anyhow::bail!("df failed for {}", path.display());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let available: u64 = stdout
+        .lines()
+        .nth(1)
+        .and_then(|l| l.split_whitespace().nth(3))
+        .and_then(|v| v.parse().ok())
+        .context("could not parse df output")?;
+    Ok(available.saturating_mul(1024))
+}
@@ -0,0 +1,651 @@
+use crate::observer::{MigrationObserver, NoopObserver, Phase};
+use crate::script::{self, ScriptItem, Word};
+use crate::{Config, db, filter::NameFilter, manifest};
+use anyhow::Result;
+use log::info;
+use sqlx::PgPool;
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::sync::Mutex;
+
+/// Removes what the target must not get from a `pg_dumpall --globals-only`
+/// script and gives renamed roles their target names.
+///
+/// The migration user's own role is left alone so its password on the target
+/// is not overwritten. Roles matching an exclude pattern are not created,
+/// altered or commented on, memberships and grants involving them are
+/// dropped, and `GRANTED BY` and tablespace `OWNER` clauses naming them are
+/// removed so the statements run as the migration user instead.
+///
+/// A renamed role is created under its new name. The dumps still refer to the
+/// source name; see [`create_placeholders`] and [`reassign_db`].
+///
+/// # Errors
+///
+/// Returns an error if an exclude pattern is invalid, the migration user is
+/// renamed, or a renamed role has an MD5 password, which is salted with the
+/// role name and would stop working.
+pub fn filter_globals(config: &Config, script: &str) -> Result<String> {
+    let filter = config.role_filter()?;
+    if config.role_renames.contains_key(&config.to_user) {
+        anyhow::bail!(
+            "cannot rename role '{}', the migration connects as it",
+            config.to_user
+        );
+    }
+    let mut excluded = BTreeSet::new();
+    let mut skipped_self = false;
+    let mut out = Vec::new();
+    for item in script::split(script) {
+        match item {
+            ScriptItem::Meta(command) => out.push(command),
+            ScriptItem::Sql(sql) => {
+                let words = script::words(&sql);
+                if let Some(role) = defined_role(&words) {
+                    let creates = words[0].is("create") || words[0].is("alter");
+                    let name = config.role_renames.get(&role.text).unwrap_or(&role.text);
+                    if creates && *name == config.to_user {
+                        skipped_self = true;
+                        continue;
+                    }
+                    if is_excluded(&filter, role) {
+                        excluded.insert(role.text.clone());
+                        continue;
+                    }
+                    if let Some(new) = config.role_renames.get(&role.text)
+                        && has_md5_password(&sql, &words)
+                    {
+                        anyhow::bail!(
+                            "role '{}' has an MD5 password, which would not work under \
+                             the name '{new}'; give it a SCRAM password on the source \
+                             or drop the rename",
+                            role.text
+                        );
+                    }
+                }
+                if let Some(sql) = filter_statement(&filter, &sql, &words, &mut excluded) {
+                    out.push(rename_roles(&config.role_renames, &sql));
+                }
+            }
+        }
+    }
+
+    if skipped_self {
+        info!(
+            "Skipping migration of role '{}' to avoid password overwrite.",
+            config.to_user
+        );
+    }
+    for role in excluded {
+        info!("Skipping migration of role '{role}', it matches an exclude pattern.");
+    }
+    Ok(out.join("\n"))
+}
+
+/// Returns true if `role` matches an exclude pattern. `PUBLIC` never does.
+fn is_excluded(filter: &NameFilter, role: &Word) -> bool {
+    !role.is("public") && !filter.matches(&role.text)
+}
+
+/// Returns the role a statement creates, alters, drops or comments on.
+fn defined_role(words: &[Word]) -> Option<&Word> {
+    let first = words.first()?;
+    if first.is("create") || first.is("alter") || first.is("drop") {
+        let kind = words.get(1)?;
+        if !(kind.is("role") || kind.is("user") || kind.is("group")) {
+            return None;
+        }
+        return words[2..].iter().find(|w| !(w.is("if") || w.is("exists")));
+    }
+    if first.is("comment") || first.is("security") {
+        let on = words.iter().position(|w| w.is("on"))?;
+        if words.get(on + 1)?.is("role") {
+            return words.get(on + 2);
+        }
+    }
+    None
+}
+
+/// Returns true if a role statement sets a password hashed with MD5.
+fn has_md5_password(sql: &str, words: &[Word]) -> bool {
+    words.iter().filter(|w| w.is("password")).any(|w| {
+        sql[w.range.end..]
+            .trim_start()
+            .get(..4)
+            .is_some_and(|s| s.eq_ignore_ascii_case("'md5"))
+    })
+}
+
+/// Splits a `GRANT` or `REVOKE` into the roles it grants membership in, empty
+/// for privileges on objects, and the roles it grants to.
+fn grant_roles(words: &[Word]) -> Option<(&[Word], &[Word])> {
+    let first = words.first()?;
+    let grant = first.is("grant");
+    if !grant && !first.is("revoke") {
+        return None;
+    }
+    let target = if grant { "to" } else { "from" };
+    let to = words.iter().position(|w| w.is(target))?;
+    let end = words[to + 1..]
+        .iter()
+        .position(|w| w.is("with") || w.is("granted"))
+        .map_or(words.len(), |p| to + 1 + p);
+    // Without ON the statement grants membership in the roles listed before
+    // TO, after `ADMIN OPTION FOR` and the like
+    let members = if words[..to].iter().any(|w| w.is("on")) {
+        &[][..]
+    } else {
+        let start = words[..to]
+            .iter()
+            .position(|w| w.is("for"))
+            .map_or(1, |p| p + 1);
+        &words[start..to]
+    };
+    Some((members, &words[to + 1..end]))
+}
+
+/// Returns the keyword starting a `GRANTED BY role` or `OWNER role` clause,
+/// picked by that keyword, and the role it names.
+fn clause_role<'a>(words: &'a [Word], keyword: &str) -> Option<(&'a Word, &'a Word)> {
+    let at = words.iter().position(|w| w.is(keyword))?;
+    let role_at = if words.get(at + 1).is_some_and(|w| w.is("by")) {
+        at + 2
+    } else {
+        at + 1
+    };
+    Some((&words[at], words.get(role_at)?))
+}
+
+/// Returns true if the statement creates a tablespace.
+fn creates_tablespace(words: &[Word]) -> bool {
+    words.first().is_some_and(|w| w.is("create"))
+        && words.get(1).is_some_and(|w| w.is("tablespace"))
+}
+
+/// Applies the exclude patterns to a statement that does not define a role;
+/// returns `None` if the whole statement has to go.
+fn filter_statement(
+    filter: &NameFilter,
+    sql: &str,
+    words: &[Word],
+    excluded: &mut BTreeSet<String>,
+) -> Option<String> {
+    if let Some((members, grantees)) = grant_roles(words) {
+        let mut roles = grantees.iter().chain(members);
+        if let Some(role) = roles.find(|r| is_excluded(filter, r)) {
+            excluded.insert(role.text.clone());
+            return None;
+        }
+        return Some(strip_role_clause(filter, sql, words, "granted", excluded));
+    }
+    if creates_tablespace(words) {
+        return Some(strip_role_clause(filter, sql, words, "owner", excluded));
+    }
+    Some(sql.to_string())
+}
+
+/// Removes a `GRANTED BY role` or `OWNER role` clause, picked by its first
+/// keyword, if the role is excluded.
+fn strip_role_clause(
+    filter: &NameFilter,
+    sql: &str,
+    words: &[Word],
+    keyword: &str,
+    excluded: &mut BTreeSet<String>,
+) -> String {
+    match clause_role(words, keyword) {
+        Some((at, role)) if is_excluded(filter, role) => {
+            excluded.insert(role.text.clone());
+            format!(
+                "{}{}",
+                sql[..at.range.start].trim_end(),
+                &sql[role.range.end..]
+            )
+        }
+        _ => sql.to_string(),
+    }
+}
+
+/// Replaces the names of renamed roles wherever a statement names a role.
+fn rename_roles(renames: &BTreeMap<String, String>, sql: &str) -> String {
+    if renames.is_empty() {
+        return sql.to_string();
+    }
+    let words = script::words(sql);
+    let mut roles: Vec<&Word> = defined_role(&words).into_iter().collect();
+    if let Some((members, grantees)) = grant_roles(&words) {
+        roles.extend(members.iter().chain(grantees));
+        roles.extend(clause_role(&words, "granted").map(|(_, role)| role));
+    } else if creates_tablespace(&words) {
+        roles.extend(clause_role(&words, "owner").map(|(_, role)| role));
+    }
+    roles.sort_by_key(|w| w.range.start);
+
+    let mut out = String::with_capacity(sql.len());
+    let mut copied = 0;
+    for role in roles {
+        if let Some(new) = renames.get(&role.text) {
+            out.push_str(&sql[copied..role.range.start]);
+            out.push_str(&quote(new));
+            copied = role.range.end;
+        }
+    }
+    out.push_str(&sql[copied..]);
+    out
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Returns the statement handing what role `from` owns to role `to`.
+#[must_use]
+pub fn reassign_statement(from: &str, to: &str) -> String {
+    format!("REASSIGN OWNED BY {} TO {}", quote(from), quote(to))
+}
+
+/// Returns what a run executes on the target for the role renames, with
+/// comments saying when.
+#[must_use]
+pub fn plan(config: &Config) -> Vec<String> {
+    let renames = &config.role_renames;
+    if renames.is_empty() {
+        return Vec::new();
+    }
+    let mut lines = vec!["-- before the restores:".to_string()];
+    lines.extend(
+        renames
+            .keys()
+            .map(|from| format!("CREATE ROLE {} NOLOGIN;", quote(from))),
+    );
+    lines.push("-- after each restore, in its database:".to_string());
+    for (from, to) in renames {
+        lines.push(format!("{};", reassign_statement(from, to)));
+        lines.push(format!("-- grant {to} every privilege {from} holds"));
+        lines.push(format!("DROP OWNED BY {};", quote(from)));
+    }
+    lines.push("-- once every database is done:".to_string());
+    lines.extend(
+        renames
+            .keys()
+            .map(|from| format!("DROP ROLE {};", quote(from))),
+    );
+    lines
+}
+
+async fn target_pool(config: &Config, db: &str) -> Result<PgPool> {
+    db::pg_pool(
+        &config.to_host,
+        &config.to_port,
+        &config.to_user,
+        &config.to_pass,
+        db,
+    )
+    .await
+}
+
+async fn role_exists(pool: &PgPool, name: &str) -> Result<bool> {
+    Ok(
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = $1)")
+            .bind(name)
+            .fetch_one(pool)
+            .await?,
+    )
+}
+
+/// Serialises the placeholder checks of restores running side by side.
+static PLACEHOLDERS: Mutex<()> = Mutex::const_new(());
+
+/// Creates the source names of renamed roles on the target as `NOLOGIN`
+/// roles, so the ownership and grants in the dumps restore, and records them
+/// in the manifest.
+///
+/// A role that already exists and was not created here is left alone, and
+/// nothing is handed over from it.
+///
+/// # Errors
+///
+/// Returns an error if the target cannot be queried or a role cannot be
+/// created.
+pub async fn create_placeholders(config: &Config) -> Result<()> {
+    if config.role_renames.is_empty() {
+        return Ok(());
+    }
+    let _guard = PLACEHOLDERS.lock().await;
+    let pool = target_pool(config, &config.to_db).await?;
+    for (from, to) in &config.role_renames {
+        if role_exists(&pool, from).await? {
+            continue;
+        }
+        sqlx::query(&format!("CREATE ROLE {} NOLOGIN", quote(from)))
+            .execute(&pool)
+            .await?;
+        manifest::update(config, |m| {
+            m.placeholder_roles.insert(from.clone(), to.clone());
+        })?;
+    }
+    Ok(())
+}
+
+/// Turns every privilege role `$1` holds in the current database, default
+/// privileges included, into a statement granting it to role `$2`.
+const GRANTS: &str = "\
+    WITH r AS (SELECT oid FROM pg_roles WHERE rolname = $1), \
+    acl (kind, name, col, acl) AS ( \
+        SELECT CASE c.relkind WHEN 'S' THEN 'SEQUENCE' ELSE 'TABLE' END, \
+               c.oid::regclass::text, NULL::name, c.relacl FROM pg_class c \
+        UNION ALL SELECT 'TABLE', a.attrelid::regclass::text, a.attname, a.attacl \
+               FROM pg_attribute a \
+        UNION ALL SELECT 'SCHEMA', quote_ident(nspname), NULL, nspacl FROM pg_namespace \
+        UNION ALL SELECT 'ROUTINE', oid::regprocedure::text, NULL, proacl FROM pg_proc \
+        UNION ALL SELECT 'TYPE', oid::regtype::text, NULL, typacl FROM pg_type \
+        UNION ALL SELECT 'LANGUAGE', quote_ident(lanname), NULL, lanacl FROM pg_language \
+        UNION ALL SELECT 'LARGE OBJECT', oid::text, NULL, lomacl \
+               FROM pg_largeobject_metadata \
+        UNION ALL SELECT 'FOREIGN DATA WRAPPER', quote_ident(fdwname), NULL, fdwacl \
+               FROM pg_foreign_data_wrapper \
+        UNION ALL SELECT 'FOREIGN SERVER', quote_ident(srvname), NULL, srvacl \
+               FROM pg_foreign_server \
+        UNION ALL SELECT 'DATABASE', quote_ident(datname), NULL, datacl FROM pg_database \
+               WHERE datname = current_database()) \
+    SELECT format('GRANT %s%s ON %s %s TO %I%s', e.privilege_type, \
+           ' (' || quote_ident(acl.col) || ')', acl.kind, acl.name, $2::text, \
+           CASE WHEN e.is_grantable THEN ' WITH GRANT OPTION' ELSE '' END) \
+    FROM acl CROSS JOIN LATERAL aclexplode(acl.acl) e, r WHERE e.grantee = r.oid \
+    UNION ALL \
+    SELECT format('ALTER DEFAULT PRIVILEGES FOR ROLE %I%s GRANT %s ON %s TO %s%s', \
+           CASE WHEN d.defaclrole = r.oid THEN $2::text \
+                ELSE pg_get_userbyid(d.defaclrole)::text END, \
+           ' IN SCHEMA ' || quote_ident(n.nspname), e.privilege_type, \
+           CASE d.defaclobjtype WHEN 'r' THEN 'TABLES' WHEN 'S' THEN 'SEQUENCES' \
+                WHEN 'f' THEN 'FUNCTIONS' WHEN 'T' THEN 'TYPES' ELSE 'SCHEMAS' END, \
+           CASE WHEN e.grantee = r.oid THEN quote_ident($2::text) \
+                WHEN e.grantee = 0 THEN 'PUBLIC' \
+                ELSE quote_ident(pg_get_userbyid(e.grantee)::text) END, \
+           CASE WHEN e.is_grantable THEN ' WITH GRANT OPTION' ELSE '' END) \
+    FROM pg_default_acl d LEFT JOIN pg_namespace n ON n.oid = d.defaclnamespace \
+    CROSS JOIN LATERAL aclexplode(d.defaclacl) e, r \
+    WHERE d.defaclrole = r.oid OR e.grantee = r.oid";
+
+/// Hands what the placeholder roles own in the target database of `db` to
+/// the renamed roles.
+///
+/// The renamed roles also get the placeholders' privileges, then those are
+/// dropped, all in one transaction. Runs once per restore; the manifest
+/// records that it is done.
+///
+/// # Errors
+///
+/// Returns an error if the target database cannot be changed, or a renamed
+/// role does not exist on the target, e.g. because the globals were not
+/// migrated.
+pub async fn reassign_db(config: &Config, db: &str) -> Result<()> {
+    let manifest = manifest::load(config)?;
+    if manifest.placeholder_roles.is_empty()
+        || manifest
+            .database(config, db)
+            .is_some_and(|r| r.roles_reassigned)
+    {
+        return Ok(());
+    }
+    let pool = target_pool(config, config.target_db(db)).await?;
+    for (from, to) in &manifest.placeholder_roles {
+        if !role_exists(&pool, from).await? {
+            continue;
+        }
+        if !role_exists(&pool, to).await? {
+            anyhow::bail!("role '{to}' does not exist on the target, migrate the globals first");
+        }
+        let mut tx = pool.begin().await?;
+        sqlx::query(&reassign_statement(from, to))
+            .execute(&mut *tx)
+            .await?;
+        let grants: Vec<String> = sqlx::query_scalar(GRANTS)
+            .bind(from)
+            .bind(to)
+            .fetch_all(&mut *tx)
+            .await?;
+        for grant in grants {
+            sqlx::query(&grant).execute(&mut *tx).await?;
+        }
+        sqlx::query(&format!("DROP OWNED BY {}", quote(from)))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+    manifest::update_database(config, db, |r| r.roles_reassigned = true)?;
+    info!("Handed the objects of renamed roles in {db} to their new names.");
+    Ok(())
+}
+
+/// Drops the placeholder roles once every database in `db_names` has been
+/// handed over to the renamed roles.
+///
+/// A placeholder that cannot be dropped, e.g. because something outside the
+/// migrated databases still depends on it, is left with a warning.
+///
+/// # Errors
+///
+/// Returns an error if the target cannot be reached.
+pub async fn drop_placeholders(
+    config: &Config,
+    db_names: &[String],
+    observer: &dyn MigrationObserver,
+) -> Result<()> {
+    let manifest = manifest::load(config)?;
+    let pending = db_names.iter().any(|db| {
+        !manifest
+            .database(config, db)
+            .is_some_and(|r| r.roles_reassigned)
+    });
+    if manifest.placeholder_roles.is_empty() || pending {
+        return Ok(());
+    }
+    let pool = target_pool(config, &config.to_db).await?;
+    for from in manifest.placeholder_roles.keys() {
+        match sqlx::query(&format!("DROP ROLE IF EXISTS {}", quote(from)))
+            .execute(&pool)
+            .await
+        {
+            Ok(_) => manifest::update(config, |m| {
+                m.placeholder_roles.remove(from);
+            })?,
+            Err(e) => observer.warning(
+                None,
+                &format!("placeholder role '{from}' was not dropped: {e}"),
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Finishes the role renames an earlier run left halfway.
+///
+/// Hands over every restored database that still needs it, then drops the
+/// placeholder roles if every database is restored. Returns the statements
+/// used; with `dry_run` nothing is changed.
+///
+/// # Errors
+///
+/// Returns an error if the target cannot be queried or changed.
+pub async fn recover(config: &Config) -> Result<Vec<String>> {
+    let manifest = manifest::load(config)?;
+    if manifest.placeholder_roles.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut statements = Vec::new();
+    let mut restored = Vec::new();
+    for (db, record) in &manifest.databases {
+        if !manifest.is_finished(config, db, Phase::Restore) {
+            continue;
+        }
+        restored.push(db.clone());
+        if record.roles_reassigned {
+            continue;
+        }
+        if !config.dry_run {
+            reassign_db(config, db).await?;
+        }
+        statements.push(format!("-- in {}:", record.target));
+        for (from, to) in &manifest.placeholder_roles {
+            statements.push(format!("{};", reassign_statement(from, to)));
+            statements.push(format!("DROP OWNED BY {};", quote(from)));
+        }
+    }
+    if restored.len() < manifest.databases.len() {
+        statements.push("-- placeholder roles kept, not every database is restored".to_string());
+        return Ok(statements);
+    }
+    if !config.dry_run {
+        drop_placeholders(config, &restored, &NoopObserver).await?;
+    }
+    let left = manifest::load(config)?.placeholder_roles;
+    for from in manifest.placeholder_roles.keys() {
+        statements.push(format!("DROP ROLE {};", quote(from)));
+        if !config.dry_run && left.contains_key(from) {
+            statements.push("-- failed, the role is still in use".to_string());
+        }
+    }
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            to_user: "postgres".to_string(),
+            exclude_roles: vec!["rds*".to_string()],
+            ..Config::default()
+        }
+    }
+
+    fn filter(script: &str) -> String {
+        filter_globals(&config(), script).expect("valid patterns")
+    }
+
+    #[test]
+    fn strips_granted_by_excluded_role() {
+        assert_eq!(
+            filter("GRANT alice TO tricky WITH INHERIT TRUE GRANTED BY rdsadmin;"),
+            "GRANT alice TO tricky WITH INHERIT TRUE;"
+        );
+        assert_eq!(
+            filter("GRANT alice TO tricky GRANTED BY bob;"),
+            "GRANT alice TO tricky GRANTED BY bob;"
+        );
+    }
+
+    #[test]
+    fn drops_grants_involving_excluded_roles() {
+        assert_eq!(filter("GRANT rds_superuser TO alice;"), "");
+        assert_eq!(filter("GRANT alice TO rdsadmin, tricky;"), "");
+        assert_eq!(
+            filter("GRANT CREATE ON TABLESPACE ts TO alice;"),
+            "GRANT CREATE ON TABLESPACE ts TO alice;"
+        );
+    }
+
+    #[test]
+    fn strips_tablespace_owner_excluded_role() {
+        assert_eq!(
+            filter("CREATE TABLESPACE ts OWNER rdsadmin LOCATION '/data/ts';"),
+            "CREATE TABLESPACE ts LOCATION '/data/ts';"
+        );
+        assert_eq!(
+            filter("CREATE TABLESPACE ts OWNER alice LOCATION '/data/ts';"),
+            "CREATE TABLESPACE ts OWNER alice LOCATION '/data/ts';"
+        );
+    }
+
+    #[test]
+    fn skips_excluded_role_definitions() {
+        assert_eq!(
+            filter(
+                "CREATE ROLE rdsadmin;\nALTER ROLE rdsadmin WITH SUPERUSER;\n\
+                 COMMENT ON ROLE rdsadmin IS 'x';\nCREATE ROLE alice;"
+            ),
+            "CREATE ROLE alice;"
+        );
+    }
+
+    #[test]
+    fn skips_the_migration_user() {
+        assert_eq!(
+            filter(
+                "CREATE ROLE postgres;\nALTER ROLE postgres WITH LOGIN PASSWORD 'secret';\n\
+                 GRANT alice TO postgres;"
+            ),
+            "GRANT alice TO postgres;"
+        );
+        assert_eq!(
+            filter("CREATE ROLE \"Postgres\";"),
+            "CREATE ROLE \"Postgres\";"
+        );
+    }
+
+    fn renaming() -> Config {
+        Config {
+            role_renames: [("olduser".to_string(), "new user".to_string())].into(),
+            ..config()
+        }
+    }
+
+    #[test]
+    fn renames_roles_wherever_they_are_named() {
+        let script = "CREATE ROLE olduser;\n\
+            ALTER ROLE olduser WITH LOGIN PASSWORD 'SCRAM-SHA-256$4096:x';\n\
+            ALTER ROLE olduser IN DATABASE olduser SET search_path TO olduser;\n\
+            COMMENT ON ROLE olduser IS 'olduser';\n\
+            GRANT olduser TO alice GRANTED BY olduser;\n\
+            GRANT alice TO olduser;\n\
+            GRANT SET ON PARAMETER work_mem TO olduser;\n\
+            CREATE TABLESPACE olduser OWNER olduser LOCATION '/olduser';";
+        assert_eq!(
+            filter_globals(&renaming(), script).expect("valid script"),
+            "CREATE ROLE \"new user\";\n\
+             ALTER ROLE \"new user\" WITH LOGIN PASSWORD 'SCRAM-SHA-256$4096:x';\n\
+             ALTER ROLE \"new user\" IN DATABASE olduser SET search_path TO olduser;\n\
+             COMMENT ON ROLE \"new user\" IS 'olduser';\n\
+             GRANT \"new user\" TO alice GRANTED BY \"new user\";\n\
+             GRANT alice TO \"new user\";\n\
+             GRANT SET ON PARAMETER work_mem TO \"new user\";\n\
+             CREATE TABLESPACE olduser OWNER \"new user\" LOCATION '/olduser';"
+        );
+    }
+
+    #[test]
+    fn refuses_to_rename_roles_with_md5_passwords() {
+        let script = "CREATE ROLE olduser;\n\
+            ALTER ROLE olduser WITH LOGIN PASSWORD 'md5c4ca4238a0b923820dcc509a6f75849b';";
+        assert!(filter_globals(&renaming(), script).is_err());
+        assert!(filter_globals(&config(), script).is_ok());
+    }
+
+    #[test]
+    fn refuses_to_rename_the_migration_user() {
+        let config = Config {
+            role_renames: [("postgres".to_string(), "admin".to_string())].into(),
+            ..config()
+        };
+        assert!(filter_globals(&config, "CREATE ROLE alice;").is_err());
+    }
+
+    #[test]
+    fn plans_placeholders_around_the_restores() {
+        assert!(plan(&config()).is_empty());
+        assert_eq!(
+            plan(&renaming()),
+            [
+                "-- before the restores:",
+                "CREATE ROLE \"olduser\" NOLOGIN;",
+                "-- after each restore, in its database:",
+                "REASSIGN OWNED BY \"olduser\" TO \"new user\";",
+                "-- grant new user every privilege olduser holds",
+                "DROP OWNED BY \"olduser\";",
+                "-- once every database is done:",
+                "DROP ROLE \"olduser\";",
+            ]
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use crate::Config;
+use crate::config::ScheduleOrder;
+use crate::filter::NameFilter;
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Sorts `dbs_with_sizes` into the order they should be started in.
+///
+/// Databases matching a `priority_dbs` pattern come first, grouped by the
+/// first pattern they match; the rest follow. Within each group the
+/// configured [`ScheduleOrder`] applies.
+///
+/// # Errors
+///
+/// Returns an error if a priority pattern is invalid.
+pub fn order(config: &Config, dbs_with_sizes: &mut [(String, u64)]) -> Result<()> {
+    let priorities = config
+        .priority_dbs
+        .iter()
+        .map(|p| NameFilter::new(std::slice::from_ref(p), &[]))
+        .collect::<Result<Vec<_>>>()?;
+    let rank = |db: &str| {
+        priorities
+            .iter()
+            .position(|f| f.matches(db))
+            .unwrap_or(priorities.len())
+    };
+
+    dbs_with_sizes.sort_by(|(a, a_size), (b, b_size)| {
+        let by_size = match config.order {
+            ScheduleOrder::LargestFirst => b_size.cmp(a_size),
+            ScheduleOrder::SmallestFirst => a_size.cmp(b_size),
+        };
+        rank(a).cmp(&rank(b)).then(by_size).then_with(|| a.cmp(b))
+    });
+    Ok(())
+}
+
+/// Returns a copy of `config` in which every database gets its share of the
+/// jobs budget as its job counts.
+///
+/// Shares are proportional to size, measured against the `max_parallel`
+/// largest databases since those can run at the same time. The `pg_dump`
+/// and `pg_restore` job counts are each capped at their share, so neither
+/// exceeds what `dump_jobs` or `restore_jobs` asks for. Every database gets
+/// at least one job; explicit per-database `jobs` are kept but capped at the
+/// budget. Without a budget the config is returned unchanged.
+#[must_use]
+pub fn with_job_shares(config: &Config, dbs_with_sizes: &[(String, u64)]) -> Config {
+    let mut config = config.clone();
+    let Some(budget) = config.jobs_budget.map(|b| b.max(1)) else {
+        return config;
+    };
+
+    let max_parallel = config.max_parallel.max(1);
+    let mut sizes: Vec<u64> = dbs_with_sizes.iter().map(|(_, s)| *s).collect();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    let peak: u128 = sizes
+        .iter()
+        .take(max_parallel)
+        .map(|s| u128::from(*s))
+        .sum();
+
+    let (dump_jobs, restore_jobs) = (config.dump_jobs, config.restore_jobs);
+    for (db, size) in dbs_with_sizes {
+        let entry = config.databases.entry(db.clone()).or_default();
+        if let Some(jobs) = entry.jobs {
+            entry.jobs = Some(jobs.clamp(1, budget));
+            continue;
+        }
+        let share = (budget as u128 * u128::from(*size))
+            .checked_div(peak)
+            .map_or(budget / max_parallel, |s| {
+                usize::try_from(s).unwrap_or(budget)
+            });
+        entry.dump_jobs = Some(share.min(dump_jobs).clamp(1, budget));
+        entry.restore_jobs = Some(share.min(restore_jobs).clamp(1, budget));
+    }
+    config
+}
+
+/// Global pool of `pg_dump`/`pg_restore` jobs shared by all running databases.
+#[derive(Clone)]
+pub struct JobsBudget {
+    sem: Arc<Semaphore>,
+    total: usize,
+}
+
+impl JobsBudget {
+    /// Creates the pool for `config.jobs_budget`, or `None` if there is no budget.
+    #[must_use]
+    pub fn new(config: &Config) -> Option<Self> {
+        config.jobs_budget.map(|total| {
+            let total = total.max(1);
+            Self {
+                sem: Arc::new(Semaphore::new(total)),
+                total,
+            }
+        })
+    }
+
+    /// Waits until the jobs `db` uses are free and reserves them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pool has been closed.
+    pub async fn acquire(&self, config: &Config, db: &str) -> Result<OwnedSemaphorePermit> {
+        let jobs = if config.stream {
+            1
+        } else {
+            config.dump_jobs_for(db).max(config.restore_jobs_for(db))
+        };
+        let jobs = u32::try_from(jobs.clamp(1, self.total)).unwrap_or(u32::MAX);
+        Ok(self.sem.clone().acquire_many_owned(jobs).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dbs(sizes: &[(&str, u64)]) -> Vec<(String, u64)> {
+        sizes.iter().map(|(n, s)| ((*n).to_string(), *s)).collect()
+    }
+
+    fn names(dbs: &[(String, u64)]) -> Vec<&str> {
+        dbs.iter().map(|(n, _)| n.as_str()).collect()
+    }
+
+    #[test]
+    fn orders_largest_first_by_default() {
+        let mut list = dbs(&[("a", 10), ("b", 30), ("c", 20), ("d", 30)]);
+        order(&Config::default(), &mut list).expect("no patterns");
+        assert_eq!(names(&list), ["b", "d", "c", "a"]);
+    }
+
+    #[test]
+    fn orders_smallest_first() {
+        let config = Config {
+            order: ScheduleOrder::SmallestFirst,
+            ..Config::default()
+        };
+        let mut list = dbs(&[("a", 10), ("b", 30), ("c", 20)]);
+        order(&config, &mut list).expect("no patterns");
+        assert_eq!(names(&list), ["a", "c", "b"]);
+    }
+
+    #[test]
+    fn orders_priority_patterns_first() {
+        let config = Config {
+            priority_dbs: vec!["billing".to_string(), "app_*".to_string()],
+            ..Config::default()
+        };
+        let mut list = dbs(&[
+            ("big", 100),
+            ("app_small", 1),
+            ("billing", 5),
+            ("app_large", 50),
+        ]);
+        order(&config, &mut list).expect("valid patterns");
+        assert_eq!(names(&list), ["billing", "app_large", "app_small", "big"]);
+    }
+
+    fn budgeted(budget: usize) -> Config {
+        Config {
+            jobs_budget: Some(budget),
+            max_parallel: 2,
+            dump_jobs: 24,
+            restore_jobs: 4,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn leaves_config_alone_without_budget() {
+        let config = with_job_shares(&Config::default(), &dbs(&[("a", 10)]));
+        assert!(config.databases.is_empty());
+        assert_eq!(config.dump_jobs_for("a"), 24);
+        assert_eq!(config.restore_jobs_for("a"), 12);
+    }
+
+    #[test]
+    fn shares_budget_by_size_per_tool() {
+        let config = with_job_shares(&budgeted(12), &dbs(&[("a", 300), ("b", 100), ("c", 1)]));
+        // Measured against a + b, the two that can run at once
+        assert_eq!(config.dump_jobs_for("a"), 9);
+        assert_eq!(config.dump_jobs_for("b"), 3);
+        assert_eq!(config.dump_jobs_for("c"), 1);
+        // The configured restore_jobs still caps the restore share
+        assert_eq!(config.restore_jobs_for("a"), 4);
+        assert_eq!(config.restore_jobs_for("b"), 3);
+        assert_eq!(config.restore_jobs_for("c"), 1);
+    }
+
+    #[test]
+    fn caps_explicit_jobs_at_budget() {
+        let mut config = budgeted(6);
+        config.databases.entry("a".to_string()).or_default().jobs = Some(16);
+        config.databases.entry("b".to_string()).or_default().jobs = Some(2);
+        let config = with_job_shares(&config, &dbs(&[("a", 1), ("b", 100)]));
+        assert_eq!(config.dump_jobs_for("a"), 6);
+        assert_eq!(config.restore_jobs_for("a"), 6);
+        assert_eq!(config.dump_jobs_for("b"), 2);
+        assert_eq!(config.restore_jobs_for("b"), 2);
+    }
+
+    #[test]
+    fn splits_budget_evenly_when_sizes_are_unknown() {
+        let config = with_job_shares(&budgeted(8), &dbs(&[("a", 0), ("b", 0)]));
+        assert_eq!(config.dump_jobs_for("a"), 4);
+        assert_eq!(config.restore_jobs_for("b"), 4);
+    }
+}
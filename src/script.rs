@@ -0,0 +1,374 @@
+use std::ops::Range;
+
+/// One piece of a `psql` script such as the output of `pg_dumpall`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScriptItem {
+    /// An SQL statement, with its terminating semicolon if it had one.
+    Sql(String),
+    /// A backslash meta-command such as `\connect`, which only `psql` runs.
+    Meta(String),
+}
+
+impl ScriptItem {
+    #[must_use]
+    pub fn text(&self) -> &str {
+        match self {
+            Self::Sql(s) | Self::Meta(s) => s,
+        }
+    }
+}
+
+/// Splits a `psql` script into statements and meta-commands.
+///
+/// A semicolon only ends a statement outside of quoted strings and
+/// identifiers, dollar quotes, comments and parentheses. A meta-command runs
+/// from a backslash at the start of a statement to the end of its line.
+/// Comments between statements are dropped.
+#[must_use]
+pub fn split(script: &str) -> Vec<ScriptItem> {
+    let bytes = script.as_bytes();
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut in_statement = false;
+    let mut depth = 0usize;
+    let mut i = 0;
+
+    // Every delimiter is ASCII, so each index the loop stops at is a char
+    // boundary of `script`
+    while i < bytes.len() {
+        let b = bytes[i];
+        let next = bytes.get(i + 1).copied();
+        if b.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        if b == b'-' && next == Some(b'-') {
+            i = line_end(bytes, i);
+            continue;
+        }
+        if b == b'/' && next == Some(b'*') {
+            i = block_comment_end(bytes, i);
+            continue;
+        }
+        if b == b'\\' && !in_statement {
+            let end = line_end(bytes, i);
+            items.push(ScriptItem::Meta(script[i..end].trim_end().to_string()));
+            i = end;
+            continue;
+        }
+        if b == b';' && depth == 0 {
+            i += 1;
+            if in_statement {
+                items.push(ScriptItem::Sql(script[start..i].to_string()));
+                in_statement = false;
+            }
+            continue;
+        }
+
+        if !in_statement {
+            start = i;
+            in_statement = true;
+        }
+        i = match b {
+            b'\'' => {
+                // E'...' strings take backslash escapes
+                let escapes = i > 0
+                    && matches!(bytes[i - 1], b'E' | b'e')
+                    && !(i > 1 && is_identifier(bytes[i - 2]));
+                quoted_end(bytes, i, b'\'', escapes)
+            }
+            b'"' => quoted_end(bytes, i, b'"', false),
+            b'$' if !(i > 0 && is_identifier(bytes[i - 1])) => {
+                dollar_tag(bytes, i).map_or(i + 1, |tag| dollar_quoted_end(bytes, i, tag))
+            }
+            b'(' => {
+                depth += 1;
+                i + 1
+            }
+            b')' => {
+                depth = depth.saturating_sub(1);
+                i + 1
+            }
+            _ => i + 1,
+        };
+    }
+    if in_statement {
+        items.push(ScriptItem::Sql(script[start..].trim_end().to_string()));
+    }
+    items
+}
+
+/// A keyword or identifier of an SQL statement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Word {
+    /// Lower-cased if bare, as written if double-quoted.
+    pub text: String,
+    pub quoted: bool,
+    /// Byte range of the word in the statement, quotes included.
+    pub range: Range<usize>,
+}
+
+impl Word {
+    /// Returns true if this is the unquoted keyword `keyword` (lower case).
+    #[must_use]
+    pub fn is(&self, keyword: &str) -> bool {
+        !self.quoted && self.text == keyword
+    }
+}
+
+/// Returns the keywords and identifiers of `statement` in order, skipping
+/// literals, comments and punctuation.
+#[must_use]
+pub fn words(statement: &str) -> Vec<Word> {
+    let bytes = statement.as_bytes();
+    let mut words = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        let next = bytes.get(i + 1).copied();
+        i = match b {
+            b'-' if next == Some(b'-') => line_end(bytes, i),
+            b'/' if next == Some(b'*') => block_comment_end(bytes, i),
+            b'\'' => quoted_end(bytes, i, b'\'', false),
+            b'"' => {
+                let end = quoted_end(bytes, i, b'"', false);
+                let inner =
+                    statement[i + 1..end.saturating_sub(1).max(i + 1)].replace("\"\"", "\"");
+                words.push(Word {
+                    text: inner,
+                    quoted: true,
+                    range: i..end,
+                });
+                end
+            }
+            b'$' => dollar_tag(bytes, i).map_or(i + 1, |tag| dollar_quoted_end(bytes, i, tag)),
+            b if is_identifier(b) && !b.is_ascii_digit() => {
+                let end = bytes[i..]
+                    .iter()
+                    .position(|&b| !is_identifier(b) && b != b'$')
+                    .map_or(bytes.len(), |p| i + p);
+                if end - i == 1 && matches!(b, b'E' | b'e') && bytes.get(end) == Some(&b'\'') {
+                    quoted_end(bytes, end, b'\'', true)
+                } else {
+                    words.push(Word {
+                        text: statement[i..end].to_ascii_lowercase(),
+                        quoted: false,
+                        range: i..end,
+                    });
+                    end
+                }
+            }
+            b if b.is_ascii_digit() => bytes[i..]
+                .iter()
+                .position(|&b| !is_identifier(b) && b != b'.')
+                .map_or(bytes.len(), |p| i + p),
+            _ => i + 1,
+        };
+    }
+    words
+}
+
+const fn is_identifier(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+/// Returns the index of the newline ending the line at `i`.
+fn line_end(bytes: &[u8], i: usize) -> usize {
+    bytes[i..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(bytes.len(), |p| i + p)
+}
+
+/// Returns the index after the block comment opened at `i`; they nest.
+fn block_comment_end(bytes: &[u8], i: usize) -> usize {
+    let mut depth = 0usize;
+    let mut j = i;
+    while j + 1 < bytes.len() {
+        match (bytes[j], bytes[j + 1]) {
+            (b'/', b'*') => {
+                depth += 1;
+                j += 2;
+            }
+            (b'*', b'/') => {
+                depth -= 1;
+                j += 2;
+                if depth == 0 {
+                    return j;
+                }
+            }
+            _ => j += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Returns the index after the string or identifier opened by `quote` at `i`.
+/// A doubled quote stands for itself.
+fn quoted_end(bytes: &[u8], i: usize, quote: u8, escapes: bool) -> usize {
+    let mut j = i + 1;
+    while j < bytes.len() {
+        match bytes[j] {
+            b'\\' if escapes => j += 2,
+            b if b == quote => {
+                if bytes.get(j + 1) == Some(&quote) {
+                    j += 2;
+                } else {
+                    return j + 1;
+                }
+            }
+            _ => j += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Returns the length of the dollar-quote delimiter (`$$` or `$tag$`) at `i`,
+/// if there is one.
+fn dollar_tag(bytes: &[u8], i: usize) -> Option<usize> {
+    // `$1` is a parameter, not a tag
+    if bytes.get(i + 1).is_some_and(u8::is_ascii_digit) {
+        return None;
+    }
+    let tag = bytes[i + 1..]
+        .iter()
+        .take_while(|&&b| is_identifier(b))
+        .count();
+    (bytes.get(i + 1 + tag) == Some(&b'$')).then_some(tag + 2)
+}
+
+/// Returns the index after the dollar-quoted string whose `len`-byte
+/// delimiter starts at `i`.
+fn dollar_quoted_end(bytes: &[u8], i: usize, len: usize) -> usize {
+    let delimiter = &bytes[i..i + len];
+    bytes[i + len..]
+        .windows(len)
+        .position(|w| w == delimiter)
+        .map_or(bytes.len(), |p| i + len + p + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sql(s: &str) -> ScriptItem {
+        ScriptItem::Sql(s.to_string())
+    }
+
+    fn meta(s: &str) -> ScriptItem {
+        ScriptItem::Meta(s.to_string())
+    }
+
+    #[test]
+    fn splits_on_semicolons() {
+        assert_eq!(
+            split("SELECT 1;\nSELECT 2;\nSELECT 3"),
+            [sql("SELECT 1;"), sql("SELECT 2;"), sql("SELECT 3")]
+        );
+    }
+
+    #[test]
+    fn keeps_dollar_quotes_whole() {
+        let function = "CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql;";
+        assert_eq!(
+            split(&format!("{function}\nSELECT 2;")),
+            [sql(function), sql("SELECT 2;")]
+        );
+    }
+
+    #[test]
+    fn keeps_tagged_dollar_quotes_whole() {
+        let block = "DO $fn$ BEGIN PERFORM $$;$$; END $fn$;";
+        assert_eq!(
+            split(&format!("{block} SELECT $1;")),
+            [sql(block), sql("SELECT $1;")]
+        );
+    }
+
+    #[test]
+    fn honours_escapes_in_e_strings() {
+        assert_eq!(
+            split(r"SELECT E'a\';b'; SELECT 'c\'; SELECT 2;"),
+            [
+                sql(r"SELECT E'a\';b';"),
+                sql(r"SELECT 'c\';"),
+                sql("SELECT 2;")
+            ]
+        );
+    }
+
+    #[test]
+    fn honours_doubled_quotes() {
+        assert_eq!(
+            split(r#"SELECT 'it''s; fine'; GRANT "semi;""role" TO b;"#),
+            [
+                sql("SELECT 'it''s; fine';"),
+                sql(r#"GRANT "semi;""role" TO b;"#)
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_nested_block_comments() {
+        assert_eq!(
+            split("/* outer /* inner; */ still; */ SELECT 1;"),
+            [sql("SELECT 1;")]
+        );
+    }
+
+    #[test]
+    fn skips_line_comments() {
+        assert_eq!(
+            split("-- drop; everything\nSELECT 1; -- done;\n"),
+            [sql("SELECT 1;")]
+        );
+    }
+
+    #[test]
+    fn keeps_passwords_with_semicolons() {
+        let statement = "ALTER ROLE alice WITH LOGIN PASSWORD 'p;w--d/*';";
+        assert_eq!(split(statement), [sql(statement)]);
+    }
+
+    #[test]
+    fn separates_meta_commands() {
+        assert_eq!(
+            split("\\restrict abc123\nSELECT 1;\n\\connect app\nSELECT 2;\n\\unrestrict abc123\n"),
+            [
+                meta("\\restrict abc123"),
+                sql("SELECT 1;"),
+                meta("\\connect app"),
+                sql("SELECT 2;"),
+                meta("\\unrestrict abc123")
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_multi_line_comments_on_roles() {
+        let comment = "COMMENT ON ROLE tricky IS 'multi;\nline; comment with $$ dollars';";
+        assert_eq!(
+            split(&format!("{comment}\nGRANT alice TO tricky;")),
+            [sql(comment), sql("GRANT alice TO tricky;")]
+        );
+    }
+
+    #[test]
+    fn words_fold_bare_and_unquote_quoted() {
+        let statement = r#"GRANT "Semi;""Role" TO Alice"#;
+        let words = words(statement);
+        let texts: Vec<&str> = words.iter().map(|w| w.text.as_str()).collect();
+        assert_eq!(texts, ["grant", "Semi;\"Role", "to", "alice"]);
+        assert!(words[1].quoted);
+        assert_eq!(&statement[words[1].range.clone()], r#""Semi;""Role""#);
+        assert!(words[3].is("alice"));
+    }
+
+    #[test]
+    fn words_skip_literals_and_comments() {
+        let words =
+            words("COMMENT ON ROLE r /* x */ IS E'it\\'s' -- y\n || 'z' || $t$ w $t$ || 1.5;");
+        let texts: Vec<&str> = words.iter().map(|w| w.text.as_str()).collect();
+        assert_eq!(texts, ["comment", "on", "role", "r", "is"]);
+    }
+}
@@ -0,0 +1,755 @@
+use crate::config::RestartPolicy;
+use crate::manifest::{self, OriginalSetting, SettingsSnapshot, Tuning, TuningMode};
+use crate::{Config, db};
+use anyhow::{Context, Result, bail};
+use log::{error, info, warn};
+use sqlx::PgPool;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, IsTerminal},
+    thread,
+};
+
+/// Built-in fast restore profiles, by name.
+///
+/// `conservative` keeps the target crash-safe and only trades away the last
+/// commits on a crash. `default` gives up durability like the fixed settings
+/// of earlier versions, with a smaller `maintenance_work_mem`. `aggressive`
+/// also stops autovacuum, lets WAL grow and drops to minimal WAL.
+const PROFILES: &[(&str, &[(&str, &str)])] = &[
+    (
+        "conservative",
+        &[
+            ("synchronous_commit", "off"),
+            ("autovacuum", "off"),
+            ("maintenance_work_mem", "256MB"),
+            ("max_parallel_maintenance_workers", "2"),
+            ("max_wal_size", "4GB"),
+            ("checkpoint_completion_target", "0.9"),
+        ],
+    ),
+    (
+        "default",
+        &[
+            ("fsync", "off"),
+            ("synchronous_commit", "off"),
+            ("full_page_writes", "off"),
+            ("maintenance_work_mem", "512MB"),
+            ("checkpoint_completion_target", "0.9"),
+        ],
+    ),
+    (
+        "aggressive",
+        &[
+            ("fsync", "off"),
+            ("synchronous_commit", "off"),
+            ("full_page_writes", "off"),
+            ("autovacuum", "off"),
+            ("maintenance_work_mem", "1GB"),
+            ("max_parallel_maintenance_workers", "4"),
+            ("max_wal_size", "16GB"),
+            ("checkpoint_completion_target", "0.9"),
+            ("wal_level", "minimal"),
+            ("max_wal_senders", "0"),
+        ],
+    ),
+];
+
+/// Settings whose `off` value risks data loss on a crash of the target.
+const DURABILITY_SETTINGS: [&str; 3] = ["fsync", "synchronous_commit", "full_page_writes"];
+
+/// Returns the settings of the profile named by `fast_restore_profile`, in
+/// order. A custom profile from the config file wins over a built-in one of
+/// the same name.
+///
+/// # Errors
+///
+/// Returns an error if the profile is unknown or names an invalid setting.
+pub fn profile(config: &Config) -> Result<Vec<(String, String)>> {
+    let name = &config.fast_restore_profile;
+    let settings: Vec<(String, String)> =
+        if let Some(custom) = config.fast_restore_profiles.get(name) {
+            custom.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+        } else if let Some((_, builtin)) = PROFILES.iter().find(|(n, _)| n == name) {
+            builtin
+                .iter()
+                .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+                .collect()
+        } else {
+            let mut known: Vec<&str> = PROFILES.iter().map(|(n, _)| *n).collect();
+            known.extend(config.fast_restore_profiles.keys().map(String::as_str));
+            bail!(
+                "unknown fast restore profile '{name}', expected one of: {}",
+                known.join(", ")
+            );
+        };
+    if let Some((invalid, _)) = settings.iter().find(|(k, _)| !is_setting_name(k)) {
+        bail!("invalid setting name '{invalid}' in fast restore profile '{name}'");
+    }
+    Ok(settings)
+}
+
+fn is_setting_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn set_statement(name: &str, value: &str) -> String {
+    format!(
+        "ALTER SYSTEM SET {name} TO '{}';",
+        value.replace('\'', "''")
+    )
+}
+
+/// Returns the statements that apply `settings`, in order.
+#[must_use]
+pub fn fast_restore_statements(settings: &[(String, String)]) -> Vec<String> {
+    settings
+        .iter()
+        .map(|(k, v)| set_statement(k, v))
+        .chain(std::iter::once("SELECT pg_reload_conf();".to_string()))
+        .collect()
+}
+
+/// Returns the statements that put the settings of `snapshot` back, in order.
+///
+/// A setting the DBA had set with `ALTER SYSTEM` gets that value again; any
+/// other one is reset so the configuration file or default applies.
+#[must_use]
+pub fn revert_statements(snapshot: &SettingsSnapshot) -> Vec<String> {
+    snapshot
+        .settings
+        .iter()
+        .map(|(name, original)| {
+            original.auto_conf.as_ref().map_or_else(
+                || format!("ALTER SYSTEM RESET {name};"),
+                |value| set_statement(name, value),
+            )
+        })
+        .chain(std::iter::once("SELECT pg_reload_conf();".to_string()))
+        .collect()
+}
+
+async fn target_pool(config: &Config) -> Result<PgPool> {
+    db::pg_pool(
+        &config.to_host,
+        &config.to_port,
+        &config.to_user,
+        &config.to_pass,
+        &config.to_db,
+    )
+    .await
+}
+
+/// Reads the current value and the `postgresql.auto.conf` entry of every
+/// setting in `names`.
+///
+/// # Errors
+///
+/// Returns an error if the target cannot be queried.
+pub async fn snapshot(pool: &PgPool, names: &[&str]) -> Result<SettingsSnapshot> {
+    let names: Vec<String> = names.iter().map(ToString::to_string).collect();
+    let current: Vec<(String, String, Option<String>, String, String)> = sqlx::query_as(
+        "SELECT name, setting, unit, source, context FROM pg_settings WHERE name = ANY($1)",
+    )
+    .bind(&names)
+    .fetch_all(pool)
+    .await?;
+    // Later lines of the file win, just as when the server reads it
+    let auto_conf: Vec<(String, Option<String>)> = sqlx::query_as(
+        "SELECT name, setting FROM pg_file_settings \
+         WHERE sourcefile LIKE '%postgresql.auto.conf' AND name = ANY($1) \
+         ORDER BY seqno",
+    )
+    .bind(&names)
+    .fetch_all(pool)
+    .await
+    .context("cannot read pg_file_settings; superuser is required")?;
+    let auto_conf: BTreeMap<String, Option<String>> = auto_conf.into_iter().collect();
+
+    Ok(SettingsSnapshot {
+        taken_at: manifest::now(),
+        settings: current
+            .into_iter()
+            .map(|(name, value, unit, source, context)| {
+                let original = OriginalSetting {
+                    auto_conf: auto_conf.get(&name).cloned().flatten(),
+                    value,
+                    unit,
+                    source,
+                    context,
+                };
+                (name, original)
+            })
+            .collect(),
+    })
+}
+
+/// The part of a profile the target can take now.
+struct Applicable {
+    settings: Vec<(String, String)>,
+    /// `pg_settings.context` of every applicable setting.
+    contexts: BTreeMap<String, String>,
+    /// Applicable settings that only take effect after a restart.
+    restart: Vec<String>,
+    /// Settings left out, with the reason.
+    skipped: BTreeMap<String, String>,
+}
+
+impl Applicable {
+    fn skip(&mut self, name: &str, reason: String) {
+        self.skipped.insert(name.to_string(), reason);
+    }
+}
+
+/// Returns the profile settings a run would apply to the target and those it
+/// would leave out with the reason, reading the target without changing it.
+///
+/// # Errors
+///
+/// Returns an error if the profile is invalid or the target cannot be read.
+pub async fn plan(config: &Config) -> Result<(Vec<(String, String)>, BTreeMap<String, String>)> {
+    let pool = target_pool(config).await?;
+    let applicable = applicable(config, &pool, profile(config)?).await?;
+    Ok((applicable.settings, applicable.skipped))
+}
+
+/// Sorts the profile settings into those the target can take now, those that
+/// need a restart and those that are left out.
+async fn applicable(
+    config: &Config,
+    pool: &PgPool,
+    settings: Vec<(String, String)>,
+) -> Result<Applicable> {
+    let names: Vec<String> = settings.iter().map(|(k, _)| k.clone()).collect();
+    let contexts: BTreeMap<String, String> = sqlx::query_as::<_, (String, String)>(
+        "SELECT name, context FROM pg_settings WHERE name = ANY($1)",
+    )
+    .bind(&names)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+    let mut result = Applicable {
+        settings: Vec::new(),
+        contexts: BTreeMap::new(),
+        restart: Vec::new(),
+        skipped: BTreeMap::new(),
+    };
+
+    // The server refuses to start with wal_level=minimal while it archives
+    // or keeps WAL senders
+    let mut minimal_wal = None;
+    if settings
+        .iter()
+        .any(|(k, v)| k == "wal_level" && v == "minimal")
+    {
+        let archive_mode: String = sqlx::query_scalar("SHOW archive_mode")
+            .fetch_one(pool)
+            .await?;
+        if archive_mode != "off" {
+            minimal_wal = Some(format!(
+                "the target archives WAL (archive_mode={archive_mode})"
+            ));
+        } else if !settings
+            .iter()
+            .any(|(k, v)| k == "max_wal_senders" && v == "0")
+        {
+            minimal_wal = Some("wal_level=minimal needs max_wal_senders=0".to_string());
+        }
+    }
+
+    let prompt = config.restart_policy == RestartPolicy::Prompt && io::stdin().is_terminal();
+    if config.restart_policy == RestartPolicy::Prompt && !prompt {
+        warn!(
+            "--restart-policy prompt needs an interactive terminal; skipping settings that need a restart"
+        );
+    }
+    for (name, value) in settings {
+        let context = contexts.get(&name).cloned();
+        match (&minimal_wal, context.as_deref()) {
+            (Some(reason), _) if name == "wal_level" || name == "max_wal_senders" => {
+                result.skip(&name, reason.clone());
+                continue;
+            }
+            (_, None) => {
+                result.skip(&name, "the target has no such setting".to_string());
+                continue;
+            }
+            (_, Some("internal")) => {
+                result.skip(&name, "it cannot be changed".to_string());
+                continue;
+            }
+            (_, Some("postmaster")) if !prompt => {
+                result.skip(
+                    &name,
+                    "needs a restart of the target (--restart-policy prompt)".to_string(),
+                );
+                continue;
+            }
+            (_, Some("postmaster")) => result.restart.push(name.clone()),
+            _ => {}
+        }
+        result
+            .contexts
+            .insert(name.clone(), context.unwrap_or_default());
+        result.settings.push((name, value));
+    }
+    Ok(result)
+}
+
+/// SQLSTATEs with which a target refuses `ALTER SYSTEM` or
+/// `pg_reload_conf()`: insufficient privilege, and object not in prerequisite
+/// state for `allow_alter_system = off` on `PostgreSQL` 17 and later.
+const REFUSED: [&str; 2] = ["42501", "55000"];
+
+/// Returns true if `e` is the server refusing to change its configuration.
+fn refuses_change(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .and_then(sqlx::error::DatabaseError::code)
+        .is_some_and(|code| REFUSED.contains(&&*code))
+}
+
+/// [`refuses_change`] for errors that went through `anyhow`.
+fn is_refused(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<sqlx::Error>().is_some_and(refuses_change)
+}
+
+/// Asks the operator to restart the target and waits until they confirm.
+async fn prompt_restart(config: &Config, names: &[String]) -> Result<()> {
+    warn!(
+        "Restart the target server now to apply {}, then press Enter",
+        names.join(", ")
+    );
+    tokio::task::spawn_blocking(|| io::stdin().read_line(&mut String::new())).await??;
+
+    let pending: Vec<String> =
+        sqlx::query_scalar("SELECT name FROM pg_settings WHERE name = ANY($1) AND pending_restart")
+            .bind(names)
+            .fetch_all(&target_pool(config).await?)
+            .await?;
+    if !pending.is_empty() {
+        warn!(
+            "Still waiting for a restart: {}; continuing without them",
+            pending.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Switches the target to the settings of the selected fast restore profile
+/// and records what was applied in the manifest.
+///
+/// The original settings are recorded in the manifest first. A snapshot left
+/// by an interrupted run is kept, since the server may still run with the fast
+/// settings; only settings it does not cover are added. If the target refuses
+/// `ALTER SYSTEM`, the settings a session may change are passed to
+/// `pg_restore` through `PGOPTIONS` instead.
+///
+/// # Errors
+///
+/// Returns an error if the settings cannot be read or recorded, or if any
+/// `ALTER SYSTEM` statement fails for another reason than privileges.
+pub async fn enable_fast_restore(config: &Config) -> Result<()> {
+    let pool = target_pool(config).await?;
+    let mut applicable = applicable(config, &pool, profile(config)?).await?;
+    let recorded: BTreeSet<String> = manifest::load(config)?
+        .target_settings
+        .map(|s| s.settings.into_keys().collect())
+        .unwrap_or_default();
+    let mut applied = Vec::new();
+    for (name, reason) in &applicable.skipped {
+        warn!("Skipping {name}: {reason}");
+    }
+
+    let mode = match alter_system(config, &pool, &applicable.settings, &mut applied).await {
+        Ok(()) => {
+            if !applicable.restart.is_empty() {
+                prompt_restart(config, &applicable.restart).await?;
+            }
+            TuningMode::System
+        }
+        Err(e) if is_refused(&e) => {
+            warn!("The target does not allow ALTER SYSTEM; tuning the pg_restore sessions instead");
+            undo_partial(config, &pool, &applied, &recorded).await?;
+            let settings = std::mem::take(&mut applicable.settings);
+            for (name, value) in settings {
+                if applicable.contexts.get(&name).is_some_and(|c| c == "user") {
+                    applicable.settings.push((name, value));
+                } else {
+                    warn!("Skipping {name}: needs ALTER SYSTEM");
+                    applicable.skip(&name, "needs ALTER SYSTEM".to_string());
+                }
+            }
+            TuningMode::Session
+        }
+        Err(e) => return Err(e),
+    };
+
+    let tuning = Tuning {
+        mode,
+        profile: config.fast_restore_profile.clone(),
+        applied: applicable.settings.into_iter().collect(),
+        skipped: applicable.skipped,
+    };
+    manifest::update(config, |m| m.tuning = Some(tuning))
+}
+
+/// Records the original values of `settings` and applies them with
+/// `ALTER SYSTEM`, adding the name of each setting written to `applied`.
+async fn alter_system(
+    config: &Config,
+    pool: &PgPool,
+    settings: &[(String, String)],
+    applied: &mut Vec<String>,
+) -> Result<()> {
+    let recorded = manifest::load(config)?.target_settings;
+    if recorded.is_some() {
+        info!("Keeping the target settings snapshot of an earlier run");
+    }
+    let missing: Vec<&str> = settings
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .filter(|k| {
+            !recorded
+                .as_ref()
+                .is_some_and(|s| s.settings.contains_key(*k))
+        })
+        .collect();
+    if !missing.is_empty() {
+        let fresh = snapshot(pool, &missing).await?;
+        manifest::update(config, |m| {
+            if let Some(snapshot) = &mut m.target_settings {
+                snapshot.settings.extend(fresh.settings);
+            } else {
+                m.target_settings = Some(fresh);
+            }
+        })?;
+    }
+
+    for (name, value) in settings {
+        sqlx::query(&set_statement(name, value))
+            .execute(pool)
+            .await?;
+        applied.push(name.clone());
+    }
+    sqlx::query("SELECT pg_reload_conf();")
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Puts back the settings `ALTER SYSTEM` wrote before the target refused the
+/// rest, and drops the snapshot entries of settings this run did not change.
+/// Entries left by an interrupted earlier run are kept.
+async fn undo_partial(
+    config: &Config,
+    pool: &PgPool,
+    applied: &[String],
+    recorded: &BTreeSet<String>,
+) -> Result<()> {
+    let Some(mut snapshot) = manifest::load(config)?.target_settings else {
+        return Ok(());
+    };
+    let changed = SettingsSnapshot {
+        taken_at: snapshot.taken_at,
+        settings: snapshot
+            .settings
+            .iter()
+            .filter(|(name, _)| applied.contains(name))
+            .map(|(name, original)| (name.clone(), original.clone()))
+            .collect(),
+    };
+    if !changed.settings.is_empty() {
+        warn!(
+            "Putting back {}, which the target took before refusing the rest",
+            applied.join(", ")
+        );
+        revert(pool, &changed).await?;
+    }
+    snapshot
+        .settings
+        .retain(|name, _| recorded.contains(name) && !applied.contains(name));
+    manifest::update(config, |m| {
+        m.target_settings = (!snapshot.settings.is_empty()).then_some(snapshot);
+    })
+}
+
+/// Runs the statements that put the settings of `snapshot` back.
+///
+/// A target that refuses `pg_reload_conf()` after taking the statements keeps
+/// the original values in `postgresql.auto.conf` until its next reload.
+async fn revert(pool: &PgPool, snapshot: &SettingsSnapshot) -> Result<()> {
+    let statements = revert_statements(snapshot);
+    let (reload, changes) = statements
+        .split_last()
+        .expect("revert statements end with a reload");
+    for sql in changes {
+        sqlx::query(sql).execute(pool).await?;
+    }
+    match sqlx::query(reload).execute(pool).await {
+        Ok(_) => Ok(()),
+        Err(e) if refuses_change(&e) => {
+            warn!(
+                "The target refused pg_reload_conf(); the original settings apply at its next reload"
+            );
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the `PGOPTIONS` value for `pg_restore` when the target is tuned per
+/// session, on top of any `PGOPTIONS` already set.
+///
+/// # Errors
+///
+/// Returns an error if the manifest cannot be read.
+pub fn session_options(config: &Config) -> Result<Option<String>> {
+    let Some(tuning) = manifest::load(config)?.tuning else {
+        return Ok(None);
+    };
+    if tuning.mode != TuningMode::Session || tuning.applied.is_empty() {
+        return Ok(None);
+    }
+    let mut options: Vec<String> = std::env::var("PGOPTIONS").into_iter().collect();
+    options.extend(tuning.applied.iter().map(|(name, value)| {
+        format!(
+            "-c {name}={}",
+            value.replace('\\', "\\\\").replace(' ', "\\ ")
+        )
+    }));
+    Ok(Some(options.join(" ")))
+}
+
+/// Puts back the target settings recorded by [`enable_fast_restore`] and
+/// clears the snapshot.
+///
+/// # Errors
+///
+/// Returns an error if any `ALTER SYSTEM` statement fails or the manifest
+/// cannot be updated.
+pub async fn restore_original_settings(config: &Config) -> Result<()> {
+    let Some(snapshot) = manifest::load(config)?.target_settings else {
+        return Ok(());
+    };
+    revert(&target_pool(config).await?, &snapshot).await?;
+    let restart: Vec<&str> = snapshot
+        .settings
+        .iter()
+        .filter(|(_, s)| s.context == "postmaster")
+        .map(|(k, _)| k.as_str())
+        .collect();
+    if !restart.is_empty() {
+        warn!(
+            "Restart the target server to put back {}",
+            restart.join(", ")
+        );
+    }
+    manifest::update(config, |m| m.target_settings = None)
+}
+
+/// Runs `body` with the target in fast restore mode and puts the original
+/// settings back however it ends: on success, on an error or cancellation,
+/// on a panic and when the future is dropped.
+///
+/// With `disable_dst_optimizations` the body runs on the settings as they are.
+///
+/// # Errors
+///
+/// Returns the error of `body`, or an error if the settings cannot be changed
+/// or put back.
+pub async fn with_fast_restore(
+    config: &Config,
+    body: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    if config.disable_dst_optimizations {
+        manifest::update(config, |m| {
+            m.tuning = Some(Tuning {
+                profile: config.fast_restore_profile.clone(),
+                ..Tuning::default()
+            });
+        })?;
+        return body.await;
+    }
+    // Armed before the first change, so a half-applied switch is undone too
+    let guard = FastRestoreGuard {
+        config: Some(config.clone()),
+    };
+    let result = match enable_fast_restore(config).await {
+        Ok(()) => body.await,
+        Err(e) => Err(e),
+    };
+    let reverted = guard
+        .revert()
+        .await
+        .context("could not put the target settings back; run `pg-migrate recover`");
+    match result {
+        Ok(()) => reverted,
+        Err(e) => {
+            if let Err(r) = reverted {
+                error!("{r:#}");
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Puts the original target settings back if dropped before [`Self::revert`],
+/// e.g. while unwinding from a panic.
+struct FastRestoreGuard {
+    config: Option<Config>,
+}
+
+impl FastRestoreGuard {
+    async fn revert(mut self) -> Result<()> {
+        match self.config.take() {
+            Some(config) => restore_original_settings(&config).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FastRestoreGuard {
+    fn drop(&mut self) {
+        let Some(config) = self.config.take() else {
+            return;
+        };
+        // Nothing can be awaited in `drop`, so revert on a runtime of its own
+        let reverted = thread::spawn(move || -> Result<()> {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(restore_original_settings(&config))
+        })
+        .join();
+        match reverted {
+            Ok(Ok(())) => warn!("Migration aborted; target settings put back"),
+            Ok(Err(e)) => {
+                error!("Could not put the target settings back: {e:#}; run `pg-migrate recover`");
+            }
+            Err(_) => error!("Could not put the target settings back; run `pg-migrate recover`"),
+        }
+    }
+}
+
+/// Returns the statements that take the target out of a fast restore mode an
+/// earlier run left behind, and executes them unless `dry_run` is set.
+///
+/// With a snapshot in the manifest the recorded settings are put back exactly.
+/// Without one, any durability setting turned `off` with `ALTER SYSTEM` is
+/// reset. An empty list means the target is not in fast restore mode.
+///
+/// # Errors
+///
+/// Returns an error if the target cannot be queried or changed, or the
+/// manifest cannot be read or updated.
+pub async fn recover(config: &Config) -> Result<Vec<String>> {
+    if let Some(snapshot) = manifest::load(config)?.target_settings {
+        let statements = revert_statements(&snapshot);
+        if !config.dry_run {
+            restore_original_settings(config).await?;
+        }
+        return Ok(statements);
+    }
+
+    let pool = target_pool(config).await?;
+    let names: Vec<String> = DURABILITY_SETTINGS
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let disabled: Vec<String> = sqlx::query_scalar(
+        "SELECT DISTINCT name FROM pg_file_settings \
+         WHERE sourcefile LIKE '%postgresql.auto.conf' AND name = ANY($1) \
+           AND lower(setting) IN ('off', 'false', '0') \
+         ORDER BY name",
+    )
+    .bind(&names)
+    .fetch_all(&pool)
+    .await
+    .context("cannot read pg_file_settings; superuser is required")?;
+    if disabled.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let statements: Vec<String> = disabled
+        .iter()
+        .map(|name| format!("ALTER SYSTEM RESET {name};"))
+        .chain(std::iter::once("SELECT pg_reload_conf();".to_string()))
+        .collect();
+    if !config.dry_run {
+        for sql in &statements {
+            sqlx::query(sql).execute(&pool).await?;
+        }
+    }
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use std::{borrow::Cow, error::Error as StdError, fmt};
+
+    /// A server error carrying only a SQLSTATE.
+    #[derive(Debug)]
+    struct ServerError(&'static str);
+
+    impl fmt::Display for ServerError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "SQLSTATE {}", self.0)
+        }
+    }
+
+    impl StdError for ServerError {}
+
+    impl DatabaseError for ServerError {
+        fn message(&self) -> &'static str {
+            "server error"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn server_error(code: &'static str) -> anyhow::Error {
+        sqlx::Error::Database(Box::new(ServerError(code))).into()
+    }
+
+    #[test]
+    fn insufficient_privilege_is_refused() {
+        assert!(is_refused(&server_error("42501")));
+    }
+
+    #[test]
+    fn alter_system_disabled_is_refused() {
+        assert!(is_refused(&server_error("55000")));
+    }
+
+    #[test]
+    fn other_errors_are_not_refused() {
+        assert!(!is_refused(&server_error("42601")));
+        assert!(!is_refused(&server_error("57P01")));
+        assert!(!is_refused(&sqlx::Error::PoolTimedOut.into()));
+        assert!(!is_refused(&anyhow::anyhow!("connection refused")));
+    }
+}
@@ -1,6 +1,18 @@
-use indicatif::ProgressStyle;
-use std::collections::BTreeMap;
+use crate::Config;
+use crate::compat::{Finding, Severity};
+use crate::extensions::ExtensionIssue;
+use crate::manifest::{DatabaseRecord, Manifest, StatementOutcome, TuningMode};
+use crate::observer::{MigrationObserver, Phase};
+use crate::preflight::{Check, CheckStatus};
+use crate::verification::VerificationResult;
+use indicatif::{
+    FormattedDuration, HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle,
+};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 /// Returns the style used for migration progress bars.
 ///
@@ -14,6 +26,8 @@ pub fn migration_style() -> Result<ProgressStyle, indicatif::style::TemplateErro
     )
 }
 
+/// Renders the per-table comparison and returns whether anything differs.
+#[must_use]
 pub fn render_verification_report(
     db: &str,
     src_map: &BTreeMap<String, String>,
@@ -66,3 +80,505 @@ pub fn render_verification_report(
 
     (output, mismatch)
 }
+
+/// Renders the extensions of `db` that will not come over unchanged.
+#[must_use]
+pub fn render_extension_report(db: &str, issues: &[ExtensionIssue]) -> String {
+    let mut output = format!("Extensions for {db}:\n");
+    let _ = writeln!(
+        output,
+        "{:<30} | {:<15} | Problem",
+        "Extension", "Source Version"
+    );
+    let _ = writeln!(output, "{:-<30}-|-{:-<15}-|--------", "", "");
+    for issue in issues {
+        let problem = if issue.is_fatal() {
+            format!("\x1b[31m{}\x1b[0m", issue.problem)
+        } else {
+            issue.problem.to_string()
+        };
+        let _ = writeln!(
+            output,
+            "{:<30} | {:<15} | {problem}",
+            issue.extension, issue.source_version
+        );
+    }
+    output
+}
+
+/// Renders the objects of `db` that the target version cannot take as is.
+#[must_use]
+pub fn render_compat_report(db: &str, findings: &[Finding]) -> String {
+    let mut output = format!("Compatibility for {db}:\n");
+    let _ = writeln!(output, "{:<8} | {:<50} | Problem", "Severity", "Object");
+    let _ = writeln!(output, "{:-<8}-|-{:-<50}-|--------", "", "");
+    for finding in findings {
+        let severity = match finding.severity {
+            Severity::Fatal => format!("\x1b[31m{:<8}\x1b[0m", finding.severity),
+            Severity::Warning => format!("{:<8}", finding.severity),
+        };
+        let _ = writeln!(
+            output,
+            "{severity} | {:<50} | {}",
+            finding.location, finding.detail
+        );
+    }
+    output
+}
+
+/// Renders the list of databases a run would touch and their current state.
+#[must_use]
+pub fn render_plan(
+    config: &Config,
+    manifest: &Manifest,
+    dbs_with_sizes: &[(String, u64)],
+) -> String {
+    let mut output = format!(
+        "Plan: {}:{} -> {}:{}\n",
+        config.from_host, config.from_port, config.to_host, config.to_port
+    );
+    let _ = writeln!(output, "State: {}", config.state_dir().display());
+    let _ = writeln!(
+        output,
+        "{:<30} | {:<30} | {:>10} | {:>4} | {:>4} | {:<10} | State",
+        "Source DB", "Target DB", "Size", "Dump", "Rest", "Compress"
+    );
+    let _ = writeln!(
+        output,
+        "{:-<30}-|-{:-<30}-|-{:->10}-|-{:->4}-|-{:->4}-|-{:-<10}-|--------",
+        "", "", "", "", "", ""
+    );
+
+    let mut total = 0u64;
+    for (db, size) in dbs_with_sizes {
+        total = total.saturating_add(*size);
+        let target_db = config.target_db(db);
+        let state = manifest
+            .database(config, db)
+            .map_or("pending", DatabaseRecord::state);
+        let _ = writeln!(
+            output,
+            "{db:<30} | {target_db:<30} | {:>10} | {:>4} | {:>4} | {:<10} | {state}",
+            HumanBytes(*size).to_string(),
+            config.dump_jobs_for(db),
+            config.restore_jobs_for(db),
+            config.compression_for(db),
+        );
+    }
+    let _ = writeln!(
+        output,
+        "{} databases, {} total, up to {} at a time",
+        dbs_with_sizes.len(),
+        HumanBytes(total),
+        config.max_parallel
+    );
+    output
+}
+
+/// Renders the preflight checks as a table with a closing verdict.
+#[must_use]
+pub fn render_checks(checks: &[Check]) -> String {
+    let mut output = format!("{:<6} | {:<20} | Detail\n", "Status", "Check");
+    let _ = writeln!(output, "{:-<6}-|-{:-<20}-|--------", "", "");
+    for check in checks {
+        let _ = writeln!(
+            output,
+            "{:<6} | {:<20} | {}",
+            check.status.as_str(),
+            check.name,
+            check.detail
+        );
+    }
+    let count = |status| checks.iter().filter(|c| c.status == status).count();
+    let _ = writeln!(
+        output,
+        "{} passed, {} warnings, {} failed",
+        count(CheckStatus::Pass),
+        count(CheckStatus::Warn),
+        count(CheckStatus::Fail)
+    );
+    output
+}
+
+/// Renders the final summary of a run from its manifest: how long each phase
+/// took per database and what changed after the restore.
+#[must_use]
+pub fn render_summary(manifest: &Manifest) -> String {
+    const PHASES: [Phase; 5] = [
+        Phase::Dump,
+        Phase::Restore,
+        Phase::UpdateExtensions,
+        Phase::Analyze,
+        Phase::Verify,
+    ];
+    let mut output = String::from("Summary:\n");
+    let _ = writeln!(
+        output,
+        "{:<30} | {:<30} | {:<8} | {:>10} | {:>10} | {:>10} | {:>10} | {:>10}",
+        "Source DB", "Target DB", "State", "Dump", "Restore", "Extensions", "Analyze", "Verify"
+    );
+    let _ = writeln!(
+        output,
+        "{:-<30}-|-{:-<30}-|-{:-<8}-|-{:->10}-|-{:->10}-|-{:->10}-|-{:->10}-|-{:->10}",
+        "", "", "", "", "", "", "", ""
+    );
+    for (db, record) in &manifest.databases {
+        let _ = write!(
+            output,
+            "{db:<30} | {:<30} | {:<8}",
+            record.target,
+            record.state()
+        );
+        for phase in PHASES {
+            let cell = match record.phase(phase) {
+                Some(r) if r.error.is_some() => "failed".to_string(),
+                Some(r) => r.duration().map_or_else(
+                    || "running".to_string(),
+                    |d| FormattedDuration(d).to_string(),
+                ),
+                None => "-".to_string(),
+            };
+            let _ = write!(output, " | {cell:>10}");
+        }
+        output.push('\n');
+    }
+
+    summary_errors(manifest, &mut output);
+    summary_tuning(manifest, &mut output);
+    summary_globals(manifest, &mut output);
+    summary_extensions(manifest, &mut output);
+    summary_collations(manifest, &mut output);
+    output
+}
+
+/// Appends the error of every failed step to the summary.
+fn summary_errors(manifest: &Manifest, output: &mut String) {
+    const PHASES: [Phase; 6] = [
+        Phase::Dump,
+        Phase::Restore,
+        Phase::UpdateExtensions,
+        Phase::Collations,
+        Phase::Analyze,
+        Phase::Verify,
+    ];
+    for (db, record) in &manifest.databases {
+        for phase in PHASES {
+            if let Some(error) = record.phase(phase).and_then(|r| r.error.as_deref()) {
+                let _ = writeln!(
+                    output,
+                    "  {db}: {} \x1b[31mfailed\x1b[0m: {error}",
+                    phase.as_str()
+                );
+            }
+        }
+    }
+}
+
+/// Appends the target tuning that was in effect for the restore to the summary.
+fn summary_tuning(manifest: &Manifest, output: &mut String) {
+    let Some(tuning) = &manifest.tuning else {
+        return;
+    };
+    let how = match tuning.mode {
+        TuningMode::None => {
+            output.push_str("Target tuning: none (--disable-dst-optimizations)\n");
+            return;
+        }
+        TuningMode::System => "ALTER SYSTEM",
+        TuningMode::Session => "PGOPTIONS of pg_restore, ALTER SYSTEM was refused",
+    };
+    let applied: Vec<String> = tuning
+        .applied
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect();
+    let _ = writeln!(
+        output,
+        "Target tuning: profile {} via {how}: {}",
+        tuning.profile,
+        if applied.is_empty() {
+            "nothing applied".to_string()
+        } else {
+            applied.join(", ")
+        }
+    );
+    for (name, reason) in &tuning.skipped {
+        let _ = writeln!(output, "  skipped {name}: {reason}");
+    }
+}
+
+/// Appends the outcome of the globals statements to the summary.
+fn summary_globals(manifest: &Manifest, output: &mut String) {
+    let statements = &manifest.globals_statements;
+    if statements.is_empty() {
+        return;
+    }
+    let count = |outcome| statements.iter().filter(|s| s.outcome == outcome).count();
+    let _ = writeln!(
+        output,
+        "Globals: {} applied, {} skipped, {} failed",
+        count(StatementOutcome::Applied),
+        count(StatementOutcome::Skipped),
+        count(StatementOutcome::Failed)
+    );
+    for statement in statements
+        .iter()
+        .filter(|s| s.outcome == StatementOutcome::Failed)
+    {
+        let _ = writeln!(
+            output,
+            "  \x1b[31mfailed\x1b[0m: {}: {}",
+            statement.statement.lines().next().unwrap_or_default(),
+            statement.detail.as_deref().unwrap_or_default()
+        );
+    }
+}
+
+/// Appends the extension version changes and failures to the summary.
+fn summary_extensions(manifest: &Manifest, output: &mut String) {
+    let updates: Vec<_> = manifest
+        .databases
+        .iter()
+        .flat_map(|(db, r)| r.extension_updates.iter().map(move |u| (db, u)))
+        .collect();
+    if !updates.is_empty() {
+        output.push_str("Extension updates:\n");
+        for (db, update) in updates {
+            match (&update.to, &update.error) {
+                (_, Some(error)) => {
+                    let _ = writeln!(
+                        output,
+                        "  {db}: {} {} \x1b[31mfailed\x1b[0m: {error}",
+                        update.extension, update.from
+                    );
+                }
+                (Some(to), None) => {
+                    let _ = writeln!(
+                        output,
+                        "  {db}: {} {} -> {to}",
+                        update.extension, update.from
+                    );
+                }
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+/// Appends the collations whose version changed to the summary.
+fn summary_collations(manifest: &Manifest, output: &mut String) {
+    let changes: Vec<_> = manifest
+        .databases
+        .iter()
+        .flat_map(|(db, r)| r.collation_changes.iter().map(move |c| (db, c)))
+        .collect();
+    if changes.is_empty() {
+        return;
+    }
+    output.push_str("Collation changes:\n");
+    for (db, change) in changes {
+        let version = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+        let outcome = match (&change.error, change.reindexed, change.is_stale()) {
+            (Some(error), _, _) => format!("\x1b[31mrefresh failed\x1b[0m: {error}"),
+            (None, true, _) => "reindexed".to_string(),
+            (None, false, true) => "\x1b[31mstale, not reindexed\x1b[0m".to_string(),
+            (None, false, false) => "indexes rebuilt by the restore".to_string(),
+        };
+        let _ = writeln!(
+            output,
+            "  {db}: {} source {}, recorded {}, actual {}, {} indexes, {outcome}",
+            change.collation,
+            version(&change.source_version),
+            version(&change.recorded),
+            version(&change.actual),
+            change.indexes.len()
+        );
+    }
+}
+
+/// Formats a command line so it can be copied into a POSIX shell.
+#[must_use]
+pub fn render_command(program: &str, args: &[String]) -> String {
+    let mut line = program.to_string();
+    for arg in args {
+        line.push(' ');
+        if !arg.is_empty()
+            && arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./:=,@+".contains(c))
+        {
+            line.push_str(arg);
+        } else {
+            line.push('\'');
+            line.push_str(&arg.replace('\'', "'\\''"));
+            line.push('\'');
+        }
+    }
+    line
+}
+
+/// Terminal frontend: one progress bar per database on a [`MultiProgress`].
+pub struct IndicatifObserver {
+    mp: MultiProgress,
+    bars: Mutex<HashMap<String, (ProgressBar, u64)>>,
+    /// When `ANALYZE` started per database, to show how long it took.
+    analyze_started: Mutex<HashMap<String, Instant>>,
+}
+
+impl IndicatifObserver {
+    #[must_use]
+    pub fn new(mp: MultiProgress) -> Self {
+        Self {
+            mp,
+            bars: Mutex::new(HashMap::new()),
+            analyze_started: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn bar(&self, db: &str) -> Option<(ProgressBar, u64)> {
+        self.bars
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(db)
+            .cloned()
+    }
+}
+
+impl MigrationObserver for IndicatifObserver {
+    fn databases_discovered(&self, dbs_with_sizes: &[(String, u64)]) {
+        let mut bars = self.bars.lock().unwrap_or_else(PoisonError::into_inner);
+        for (db, size) in dbs_with_sizes {
+            if bars.contains_key(db) {
+                continue;
+            }
+            let pb = self.mp.add(ProgressBar::new(0));
+            if let Ok(style) = migration_style() {
+                pb.set_style(style);
+            }
+            pb.enable_steady_tick(Duration::from_secs(1));
+            bars.insert(db.clone(), (pb, *size));
+        }
+    }
+
+    fn phase_started(&self, db: &str, phase: Phase, size: u64) {
+        let Some((pb, _)) = self.bar(db) else {
+            return;
+        };
+        let human_size = HumanBytes(size);
+        let bar_total = size.saturating_mul(2).max(100);
+        match phase {
+            Phase::Dump => {
+                pb.set_length(bar_total);
+                pb.set_message(format!("Dumping {db} ({human_size})"));
+            }
+            Phase::Restore => {
+                pb.set_length(bar_total);
+                pb.set_message(format!("Restoring {db} ({human_size})"));
+            }
+            Phase::UpdateExtensions => pb.set_message(format!("Updating extensions in {db}")),
+            Phase::Collations => pb.set_message(format!("Checking collations in {db}")),
+            Phase::Analyze => {
+                self.analyze_started
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(db.to_string(), Instant::now());
+                pb.set_message(format!("Analyzing {db}"));
+            }
+            Phase::Verify => pb.set_message(format!("Verifying {db}")),
+        }
+    }
+
+    fn phase_finished(&self, db: &str, phase: Phase) {
+        let Some((pb, size)) = self.bar(db) else {
+            return;
+        };
+        match phase {
+            Phase::Dump => pb.set_position(pb.length().unwrap_or(0) / 2),
+            Phase::Restore | Phase::UpdateExtensions | Phase::Collations => {
+                pb.set_position(pb.length().unwrap_or(0));
+                pb.finish_with_message(format!("{db} ({}) restored", HumanBytes(size)));
+            }
+            Phase::Analyze => {
+                let started = self
+                    .analyze_started
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(db);
+                let took = started.map_or_else(String::new, |s| {
+                    format!(" in {}", HumanDuration(s.elapsed()))
+                });
+                pb.finish_with_message(format!(
+                    "{db} ({}) restored, analyzed{took}",
+                    HumanBytes(size)
+                ));
+            }
+            Phase::Verify => pb.finish_with_message(format!("Migration complete for {db}")),
+        }
+    }
+
+    fn phase_skipped(&self, db: &str, phase: Phase) {
+        let Some((pb, size)) = self.bar(db) else {
+            return;
+        };
+        match phase {
+            Phase::Dump => {
+                pb.set_length(size.saturating_mul(2).max(100));
+                pb.set_position(pb.length().unwrap_or(0) / 2);
+                pb.set_message(format!("Reusing existing dump of {db}"));
+            }
+            Phase::Restore => {
+                pb.set_length(size.saturating_mul(2).max(100));
+                pb.set_position(pb.length().unwrap_or(0));
+                pb.set_message(format!("Restoration skipped (already done) for {db}"));
+            }
+            Phase::UpdateExtensions | Phase::Collations | Phase::Analyze => {}
+            Phase::Verify => pb.finish_with_message(format!("{db} already verified")),
+        }
+    }
+
+    fn tables(&self, db: &str, done: usize, total: usize) {
+        if let Some((pb, _)) = self.bar(db) {
+            pb.set_message(format!("Counting rows in {db}: {done}/{total} tables"));
+        }
+    }
+
+    fn warning(&self, db: Option<&str>, message: &str) {
+        match db {
+            Some(db) => warn!("Warning: {db}: {message}"),
+            None => warn!("Warning: {message}"),
+        }
+    }
+
+    fn extensions(&self, db: &str, issues: &[ExtensionIssue]) {
+        if !issues.is_empty() {
+            let _ = self.mp.println(render_extension_report(db, issues));
+        }
+    }
+
+    fn incompatibilities(&self, db: &str, findings: &[Finding]) {
+        if !findings.is_empty() {
+            let _ = self.mp.println(render_compat_report(db, findings));
+        }
+    }
+
+    fn verification(&self, db: &str, result: &VerificationResult) {
+        let (output, _) = render_verification_report(
+            &result.label(),
+            &result.source_counts,
+            &result.target_counts,
+        );
+        let print = |line: &str| match self.bar(db) {
+            Some((pb, _)) => pb.println(line),
+            None => {
+                let _ = self.mp.println(line);
+            }
+        };
+        print(&output);
+        if !result.mismatch {
+            print(&format!(
+                "Verified {db}: {} tables, all rows match",
+                result.source_counts.len()
+            ));
+        }
+    }
+}
@@ -1,103 +1,137 @@
 use crate::Config;
 use crate::db::pg_pool;
-use crate::tui::render_verification_report;
-use crate::verify_dir;
+use crate::manifest;
+use crate::observer::{MigrationObserver, Phase};
 use anyhow::Result;
-use indicatif::ProgressBar;
 use sqlx::Row;
-use std::collections::{BTreeMap, HashMap};
-use std::fs;
-use std::path::PathBuf;
-
-pub fn verify_marker(db: &str) -> PathBuf {
-    verify_dir().join(format!("{db}.verify"))
+use std::collections::BTreeMap;
+
+/// Outcome of comparing the row counts of one database.
+#[derive(Clone, Debug)]
+pub struct VerificationResult {
+    pub source_db: String,
+    pub target_db: String,
+    /// Row counts keyed by `schema.table`.
+    pub source_counts: BTreeMap<String, String>,
+    pub target_counts: BTreeMap<String, String>,
+    /// True if a table is missing on either side or its row count differs.
+    pub mismatch: bool,
 }
 
-pub fn src_counts_path(db: &str) -> PathBuf {
-    verify_dir().join(format!("{db}.src_counts.json"))
+impl VerificationResult {
+    /// Returns `source` or `source -> target` when the database was renamed.
+    #[must_use]
+    pub fn label(&self) -> String {
+        if self.source_db == self.target_db {
+            self.source_db.clone()
+        } else {
+            format!("{} -> {}", self.source_db, self.target_db)
+        }
+    }
 }
 
-pub fn dst_counts_path(db: &str) -> PathBuf {
-    verify_dir().join(format!("{db}.dst_counts.json"))
+/// Returns the source row counts of `db`, counting them and recording them
+/// in the manifest if needed.
+///
+/// # Errors
+///
+/// Returns an error if the counts cannot be counted or recorded.
+pub async fn source_counts(
+    config: &Config,
+    db: &str,
+    observer: &dyn MigrationObserver,
+) -> Result<BTreeMap<String, String>> {
+    let recorded = manifest::load(config)?
+        .database(config, db)
+        .and_then(|r| r.source_counts.clone());
+    if let Some(counts) = recorded {
+        return Ok(counts);
+    }
+    let counts = stat_counts(
+        &config.from_host,
+        &config.from_port,
+        &config.from_pass,
+        &config.from_user,
+        db,
+        |done, total| observer.tables(db, done, total),
+    )
+    .await?;
+    manifest::update_database(config, db, |r| r.source_counts = Some(counts.clone()))?;
+    Ok(counts)
 }
 
-#[allow(dead_code)]
-pub async fn verify_all(
+/// Counts the rows of the target database of `db`.
+///
+/// The target is counted afresh every time, so a verification that failed
+/// passes once the target is fixed.
+///
+/// # Errors
+///
+/// Returns an error if the target database cannot be counted.
+pub async fn target_counts(
     config: &Config,
-    dbs: &[String],
-    pbs: &HashMap<String, ProgressBar>,
-) -> Result<()> {
-    for db in dbs {
-        if verify_marker(db).exists() {
-            continue;
-        }
-        let pb = pbs.get(db).cloned().expect("missing pb");
-        verify_db(config, db, pb).await?;
-    }
+    db: &str,
+    observer: &dyn MigrationObserver,
+) -> Result<BTreeMap<String, String>> {
+    stat_counts(
+        &config.to_host,
+        &config.to_port,
+        &config.to_pass,
+        &config.to_user,
+        config.target_db(db),
+        |done, total| observer.tables(db, done, total),
+    )
+    .await
+}
+
+/// Compares source and target row counts of `db` and reports the result.
+///
+/// # Errors
+///
+/// Returns an error if counting fails or any table differs.
+pub async fn verify_db(config: &Config, db: &str, observer: &dyn MigrationObserver) -> Result<()> {
+    observer.phase_started(db, Phase::Verify, 0);
+    manifest::phase_started(config, db, Phase::Verify)?;
+
+    let result = compare_counts(config, db, observer).await;
+    manifest::phase_finished(config, db, Phase::Verify, &result)?;
+    result?;
+
+    observer.phase_finished(db, Phase::Verify);
     Ok(())
 }
 
-pub async fn verify_db(config: &Config, db: &str, pb: ProgressBar) -> Result<()> {
-    let src_counts_path = src_counts_path(db);
-    let dst_counts_path = dst_counts_path(db);
-
-    let src_map: BTreeMap<String, String> = if src_counts_path.exists() {
-        let content = fs::read_to_string(&src_counts_path)?;
-        serde_json::from_str(&content)?
-    } else {
-        let counts = stat_counts(
-            &config.from_host,
-            &config.from_port,
-            &config.from_pass,
-            &config.from_user,
-            db,
-        )
-        .await?;
-        let content = serde_json::to_string(&counts)?;
-        fs::write(&src_counts_path, content)?;
-        counts
-    };
+async fn compare_counts(config: &Config, db: &str, observer: &dyn MigrationObserver) -> Result<()> {
+    let source_counts = source_counts(config, db, observer).await?;
+    let target_counts = target_counts(config, db, observer).await?;
 
-    let dst_map: BTreeMap<String, String> = if dst_counts_path.exists() {
-        let content = fs::read_to_string(&dst_counts_path)?;
-        serde_json::from_str(&content)?
-    } else {
-        let counts = stat_counts(
-            &config.to_host,
-            &config.to_port,
-            &config.to_pass,
-            &config.to_user,
-            db,
-        )
-        .await?;
-        let content = serde_json::to_string(&counts)?;
-        fs::write(&dst_counts_path, content)?;
-        counts
+    let result = VerificationResult {
+        source_db: db.to_string(),
+        target_db: config.target_db(db).to_string(),
+        mismatch: source_counts != target_counts,
+        source_counts,
+        target_counts,
     };
+    observer.verification(db, &result);
 
-    let (output, mismatch) = render_verification_report(db, &src_map, &dst_map);
-
-    if mismatch {
-        pb.println(&output);
+    if result.mismatch {
         anyhow::bail!("Verification failed for {db}: tables or row counts mismatch");
     }
-
-    pb.println(&output);
-    pb.println(format!(
-        "Verified {db}: {} tables, all rows match",
-        src_map.len()
-    ));
-    fs::write(verify_marker(db), "")?;
-    pb.finish_with_message(format!("Migration complete for {db}"));
     Ok(())
 }
 
+/// Counts the rows of every user table in `db`, keyed by `schema.table`.
+///
+/// # Errors
+///
+/// Returns an error if the database cannot be queried.
 pub async fn stat_counts(
     host: &str,
     port: &str,
     pass: &str,
     user: &str,
     db: &str,
+    on_table: impl Fn(usize, usize),
 ) -> Result<BTreeMap<String, String>> {
     let pool = pg_pool(host, port, user, pass, db).await?;
 
@@ -107,7 +141,8 @@ pub async fn stat_counts(
 
     let mut counts = BTreeMap::new();
 
-    for row in tables {
+    let total = tables.len();
+    for (i, row) in tables.into_iter().enumerate() {
         let schema: String = row.get(0);
         let table: String = row.get(1);
 
@@ -115,6 +150,7 @@ pub async fn stat_counts(
         let count_query = format!("SELECT count(*) FROM {full_name}");
         let count: i64 = sqlx::query(&count_query).fetch_one(&pool).await?.get(0);
         counts.insert(format!("{schema}.{table}"), count.to_string());
+        on_table(i + 1, total);
     }
 
     Ok(counts)